@@ -0,0 +1,333 @@
+use semver::VersionReq;
+
+use crate::{
+    error::SshdCommandError,
+    frontmatter::{
+        Delimiters, FrontMatter, FrontMatterTokens, OpensshVersion, Output,
+        UnknownUser,
+    },
+    render_parsed, Command, RenderOptions, Token,
+};
+
+/// Render a template body without front matter, configuring the options
+/// that would otherwise be read from the `sshd_command` front matter.
+///
+/// # Examples
+///
+/// ```
+/// use sshd_command::{builder::RenderBuilder, Command, Token};
+///
+/// let args = ["user".to_string()].into_iter();
+/// let rendered = RenderBuilder::new(Command::Principals)
+///     .tokens([Token::UserName])
+///     .context("domain", "home.arpa")
+///     .render(args, "{{ user.name }}@{{ domain }}")
+///     .unwrap();
+///
+/// assert_eq!(rendered, "user@home.arpa");
+/// ```
+#[derive(Debug, Default)]
+pub struct RenderBuilder {
+    front_matter: FrontMatter,
+    options: RenderOptions,
+}
+
+impl RenderBuilder {
+    #[must_use]
+    pub fn new(command: Command) -> Self {
+        Self::default().command(command)
+    }
+
+    #[must_use]
+    pub const fn command(mut self, command: Command) -> Self {
+        self.front_matter.sshd_command.command = command;
+        self
+    }
+
+    #[must_use]
+    pub fn tokens<T: Into<Box<[Token]>>>(mut self, tokens: T) -> Self {
+        self.front_matter.sshd_command.tokens =
+            FrontMatterTokens(tokens.into());
+        self
+    }
+
+    #[must_use]
+    pub fn version_req(mut self, version_req: VersionReq) -> Self {
+        self.front_matter.sshd_command.version = version_req;
+        self
+    }
+
+    /// Don't fail on a version that doesn't match the running version.
+    #[must_use]
+    pub const fn ignore_version(mut self, ignore_version: bool) -> Self {
+        self.options.ignore_version = ignore_version;
+        self
+    }
+
+    #[must_use]
+    pub const fn complete_user(mut self, complete_user: bool) -> Self {
+        self.front_matter.sshd_command.complete_user = complete_user;
+        self
+    }
+
+    #[must_use]
+    pub const fn hostname(mut self, hostname: bool) -> Self {
+        self.front_matter.sshd_command.hostname = hostname;
+        self
+    }
+
+    #[must_use]
+    pub const fn hostname_fqdn(mut self, hostname_fqdn: bool) -> Self {
+        self.front_matter.sshd_command.hostname_fqdn = hostname_fqdn;
+        self
+    }
+
+    #[must_use]
+    pub const fn strict_key_types(mut self, strict_key_types: bool) -> Self {
+        self.front_matter.sshd_command.strict_key_types = strict_key_types;
+        self
+    }
+
+    #[must_use]
+    pub const fn verify_key_type(mut self, verify_key_type: bool) -> Self {
+        self.front_matter.sshd_command.verify_key_type = verify_key_type;
+        self
+    }
+
+    #[must_use]
+    pub const fn ignore_unknown_tokens(
+        mut self,
+        ignore_unknown_tokens: bool,
+    ) -> Self {
+        self.front_matter.sshd_command.ignore_unknown_tokens =
+            ignore_unknown_tokens;
+        self
+    }
+
+    #[must_use]
+    pub const fn strict_context(mut self, strict_context: bool) -> Self {
+        self.front_matter.sshd_command.strict_context = strict_context;
+        self
+    }
+
+    #[must_use]
+    pub const fn env_fallback(mut self, env_fallback: bool) -> Self {
+        self.front_matter.sshd_command.env_fallback = env_fallback;
+        self
+    }
+
+    #[must_use]
+    pub const fn min_uid(mut self, min_uid: u32) -> Self {
+        self.front_matter.sshd_command.min_uid = Some(min_uid);
+        self
+    }
+
+    #[must_use]
+    pub const fn max_uid(mut self, max_uid: u32) -> Self {
+        self.front_matter.sshd_command.max_uid = Some(max_uid);
+        self
+    }
+
+    #[must_use]
+    pub const fn require_home(mut self, require_home: bool) -> Self {
+        self.front_matter.sshd_command.require_home = require_home;
+        self
+    }
+
+    #[must_use]
+    pub const fn normalize_output(mut self, normalize_output: bool) -> Self {
+        self.front_matter.sshd_command.normalize_output = normalize_output;
+        self
+    }
+
+    /// Fail when arguments are left over after the last token.
+    #[must_use]
+    pub const fn strict_args(mut self, strict_args: bool) -> Self {
+        self.options.strict_args = strict_args;
+        self
+    }
+
+    /// Trace the consumed arguments, the context and the render to stderr.
+    #[must_use]
+    pub const fn debug(mut self, debug: bool) -> Self {
+        self.options.debug = debug;
+        self
+    }
+
+    #[must_use]
+    pub const fn autoescape(mut self, autoescape: bool) -> Self {
+        self.front_matter.sshd_command.autoescape = autoescape;
+        self
+    }
+
+    #[must_use]
+    pub const fn unknown_user(mut self, unknown_user: UnknownUser) -> Self {
+        self.front_matter.sshd_command.unknown_user = unknown_user;
+        self
+    }
+
+    /// Fail when the output exceeds `max_output_bytes`, rendering stops
+    /// and nothing is written.
+    #[must_use]
+    pub const fn max_output_bytes(mut self, max_output_bytes: usize) -> Self {
+        self.front_matter.sshd_command.max_output_bytes =
+            Some(max_output_bytes);
+        self
+    }
+
+    /// Fail validation on tokens an older OpenSSH doesn't pass.
+    #[must_use]
+    pub const fn openssh_min(mut self, openssh_min: OpensshVersion) -> Self {
+        self.front_matter.sshd_command.openssh_min = Some(openssh_min);
+        self
+    }
+
+    /// Add the reverse DNS hostname of the `%C` client as
+    /// `client_hostname`.
+    #[must_use]
+    pub const fn resolve_client_hostname(
+        mut self,
+        resolve_client_hostname: bool,
+    ) -> Self {
+        self.front_matter.sshd_command.resolve_client_hostname =
+            resolve_client_hostname;
+        self
+    }
+
+    /// Wait at most `resolve_timeout_ms` for the reverse DNS lookup of
+    /// `resolve_client_hostname`, instead of 1000.
+    #[must_use]
+    pub const fn resolve_timeout_ms(
+        mut self,
+        resolve_timeout_ms: u64,
+    ) -> Self {
+        self.front_matter.sshd_command.resolve_timeout_ms =
+            Some(resolve_timeout_ms);
+        self
+    }
+
+    /// Fail before rendering when the context lacks any of the keys.
+    #[must_use]
+    pub fn required_context(mut self, required_context: Vec<String>) -> Self {
+        self.front_matter.sshd_command.required_context = required_context;
+        self
+    }
+
+    #[must_use]
+    pub const fn min_group_gid(mut self, min_group_gid: u32) -> Self {
+        self.front_matter.sshd_command.min_group_gid = Some(min_group_gid);
+        self
+    }
+
+    #[must_use]
+    pub fn groups_filter(mut self, groups_filter: Vec<String>) -> Self {
+        self.front_matter.sshd_command.groups_filter = Some(groups_filter);
+        self
+    }
+
+    #[must_use]
+    pub const fn output(mut self, output: Output) -> Self {
+        self.front_matter.sshd_command.output = output;
+        self
+    }
+
+    #[must_use]
+    pub fn delimiters(mut self, delimiters: Delimiters) -> Self {
+        self.front_matter.sshd_command.delimiters = delimiters;
+        self
+    }
+
+    /// Set a fallback argument for `token`, used when the argument is
+    /// missing.
+    #[must_use]
+    pub fn default_arg<T: Into<String>>(
+        mut self,
+        token: Token,
+        arg: T,
+    ) -> Self {
+        self.front_matter
+            .sshd_command
+            .defaults
+            .insert(token, arg.into());
+        self
+    }
+
+    /// Add an extra context value, like a custom front matter key.
+    #[must_use]
+    pub fn context<K: Into<String>, V: Into<serde_json::Value>>(
+        mut self,
+        key: K,
+        value: V,
+    ) -> Self {
+        let extra_context = self.front_matter.extra_context_mut();
+        if !extra_context.is_object() {
+            *extra_context = serde_json::Value::Object(serde_json::Map::new());
+        }
+        extra_context[key.into()] = value.into();
+        self
+    }
+
+    /// Get the [`FrontMatter`] this builder describes.
+    #[must_use]
+    pub fn build(self) -> FrontMatter {
+        self.front_matter
+    }
+
+    /// Get the [`RenderOptions`] to render the built [`FrontMatter`] with.
+    #[must_use]
+    pub const fn options(&self) -> RenderOptions {
+        self.options
+    }
+
+    /// Render `body` with the configured front matter.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` on invalid options, arguments or template body.
+    pub fn render<I: Iterator<Item = String>>(
+        self,
+        args: I,
+        body: &str,
+    ) -> Result<String, SshdCommandError> {
+        let mut buf = Vec::new();
+        render_parsed(
+            &mut buf,
+            args,
+            "template",
+            self.front_matter,
+            self.options,
+            body,
+        )?;
+
+        String::from_utf8(buf)
+            .map_err(|e| SshdCommandError::Unknown(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_render() {
+        let rendered = RenderBuilder::new(Command::Principals)
+            .tokens([Token::UserId, Token::UserName])
+            .context("search_domains", vec!["home.arpa", "local"])
+            .render(
+                [String::from("1000"), String::from("user")].into_iter(),
+                "{% for domain in search_domains %}\
+                 {{ user.name }}@{{ domain }}\n{% endfor %}",
+            );
+
+        assert_eq!(rendered.unwrap(), "user@home.arpa\nuser@local\n");
+    }
+
+    #[test]
+    fn check_render_unsupported_token() {
+        let rendered = RenderBuilder::new(Command::Keys)
+            .tokens([Token::CaKeyType])
+            .render(std::iter::empty(), "");
+
+        assert!(matches!(rendered, Err(SshdCommandError::FrontMatter(_))));
+    }
+}
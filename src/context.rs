@@ -1,15 +1,243 @@
-use std::net::{IpAddr, SocketAddr};
+use std::{
+    env, fs,
+    io::Read,
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    process::{self, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use serde::Serialize;
 use tera::Context;
-use uzers::{
-    get_current_uid, get_current_username, get_user_by_name, get_user_by_uid,
-};
+use uzers::{get_group_by_gid, get_user_by_name, get_user_by_uid};
 
 use crate::{
-    error::SshdCommandError, frontmatter::FrontMatter, macros::next_arg, Token,
+    error::SshdCommandError,
+    frontmatter::{FrontMatter, FrontMatterError, UnknownUser},
+    macros::{debug, next_arg},
+    RenderOptions, Token,
 };
 
+/// The `getent` of glibc and musl based systems, for the reverse DNS lookup
+/// of `resolve_client_hostname`.
+const GETENT: &str = "/usr/bin/getent";
+
+/// Key and certificate types known to OpenSSH, used when
+/// `strict_key_types` is enabled.
+const KEY_TYPES: &[&str] = &[
+    "ssh-ed25519",
+    "ssh-ed25519-cert-v01@openssh.com",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ssh-ed25519-cert-v01@openssh.com",
+    "ssh-rsa",
+    "ssh-rsa-cert-v01@openssh.com",
+    "rsa-sha2-256",
+    "rsa-sha2-256-cert-v01@openssh.com",
+    "rsa-sha2-512",
+    "rsa-sha2-512-cert-v01@openssh.com",
+    "ssh-dss",
+    "ssh-dss-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp256-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp384-cert-v01@openssh.com",
+    "ecdsa-sha2-nistp521",
+    "ecdsa-sha2-nistp521-cert-v01@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+    "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com",
+];
+
+/// Context keys computed by [`build_context`] that aren't the
+/// [`Token::context_keys`] of a known token.
+const NON_TOKEN_CONTEXT_KEYS: &[&str] = &["user", "hostname", "hostname_fqdn"];
+
+/// Whether `key` is computed by [`build_context`], with `strict_context`
+/// these can't be used by the front matter.
+fn is_reserved_context_key(key: &str) -> bool {
+    Token::ALL
+        .iter()
+        .flat_map(Token::context_keys)
+        .chain(NON_TOKEN_CONTEXT_KEYS)
+        .any(|reserved| *reserved == key)
+}
+
+/// How long `resolve_client_hostname` waits for the reverse DNS lookup by
+/// default.
+const DEFAULT_RESOLVE_TIMEOUT_MS: u64 = 1000;
+
+/// Resolver configuration, used to look up the domain of the system.
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// The hostname of the system, as a short name and fully-qualified domain
+/// name.
+#[derive(Debug, PartialEq, Eq)]
+struct Hostname {
+    short: String,
+    fqdn: String,
+}
+
+impl Hostname {
+    fn get() -> Result<Self, SshdCommandError> {
+        let hostname =
+            hostname::get().map_err(|_| "Failed to get hostname")?;
+        // A missing resolv.conf just means the domain is unknown
+        let resolv_conf = fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+
+        Ok(Self::resolve(&hostname.to_string_lossy(), &resolv_conf))
+    }
+
+    /// Split `hostname` into its short name and FQDN, qualifying an
+    /// unqualified `hostname` with the domain from `resolv_conf`.
+    fn resolve(hostname: &str, resolv_conf: &str) -> Self {
+        let hostname = hostname.trim_end_matches('.');
+
+        if let Some((short, _)) = hostname.split_once('.') {
+            return Self {
+                short: short.to_string(),
+                fqdn: hostname.to_string(),
+            };
+        }
+
+        // Like the resolver, the last `domain` or `search` line wins and the
+        // first search domain is the local domain
+        let domain = resolv_conf
+            .lines()
+            .rev()
+            .find_map(|line| {
+                let mut words = line.split_whitespace();
+                match words.next() {
+                    Some("domain" | "search") => words.next(),
+                    _ => None,
+                }
+            })
+            .map(|domain| domain.trim_end_matches('.'))
+            .filter(|domain| !domain.is_empty());
+
+        Self {
+            short: hostname.to_string(),
+            fqdn: domain.map_or_else(
+                || hostname.to_string(),
+                |domain| format!("{hostname}.{domain}"),
+            ),
+        }
+    }
+}
+
+/// An [`IpAddr`] with the optional zone/scope id of a link-local IPv6
+/// address, e.g. `fe80::1%eth0`.
+struct ScopedIpAddr {
+    addr: IpAddr,
+    scope: Option<String>,
+}
+
+impl FromStr for ScopedIpAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('%') {
+            Some((addr, scope)) if !scope.is_empty() => Ok(Self {
+                addr: IpAddr::V6(addr.parse::<Ipv6Addr>()?),
+                scope: Some(scope.to_string()),
+            }),
+            _ => Ok(Self {
+                addr: s.parse()?,
+                scope: None,
+            }),
+        }
+    }
+}
+
+/// Lookups in the user and hosts database, every call may hit NSS (and with
+/// it e.g. LDAP or DNS), so callers should look up a user at most once.
+///
+/// Abstracted so tests can provide a fixed user and hosts database.
+pub trait UserBackend {
+    fn user_by_uid(&self, uid: u32) -> Option<uzers::User>;
+
+    fn user_by_name(&self, name: &str) -> Option<uzers::User>;
+
+    fn user_groups(&self, user: &uzers::User) -> Option<Vec<uzers::Group>>;
+
+    fn group_by_gid(&self, gid: u32) -> Option<uzers::Group>;
+
+    /// The hostname of `addr` by reverse DNS, `None` when it has none or
+    /// the lookup takes longer than `timeout`.
+    ///
+    /// Backends without reverse DNS don't resolve any address.
+    fn hostname_by_addr(
+        &self,
+        _addr: IpAddr,
+        _timeout: Duration,
+    ) -> Option<String> {
+        None
+    }
+}
+
+/// [`UserBackend`] using the system user database through [`uzers`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UzersBackend;
+
+impl UserBackend for UzersBackend {
+    fn user_by_uid(&self, uid: u32) -> Option<uzers::User> {
+        get_user_by_uid(uid)
+    }
+
+    fn user_by_name(&self, name: &str) -> Option<uzers::User> {
+        get_user_by_name(name)
+    }
+
+    fn user_groups(&self, user: &uzers::User) -> Option<Vec<uzers::Group>> {
+        user.groups()
+    }
+
+    fn group_by_gid(&self, gid: u32) -> Option<uzers::Group> {
+        get_group_by_gid(gid)
+    }
+
+    /// Look up `addr` with `getent hosts`, which goes through NSS like the
+    /// `UseDNS` lookup of sshd, killed once `timeout` passes.
+    ///
+    /// `getent` is run by its absolute path with an empty environment, so
+    /// neither `PATH` nor e.g. `LD_PRELOAD` of the caller can swap it out.
+    fn hostname_by_addr(
+        &self,
+        addr: IpAddr,
+        timeout: Duration,
+    ) -> Option<String> {
+        let mut child = process::Command::new(GETENT)
+            .args(["hosts", &addr.to_string()])
+            .env_clear()
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+
+        // The output is a single line which fits in the pipe buffer, so
+        // getent exits without it being read
+        let deadline = Instant::now() + timeout;
+        while child.try_wait().ok()?.is_none() {
+            if Instant::now() >= deadline {
+                _ = child.kill();
+                _ = child.wait();
+                return None;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut output = String::new();
+        child.stdout.take()?.read_to_string(&mut output).ok()?;
+
+        // `<address> <hostname> [<alias>]...`, nothing when not found
+        output.split_whitespace().nth(1).map(String::from)
+    }
+}
+
+/// The user in the context, `name` and `uid` are always present (`null` when
+/// not given by `%u`/`%U`), the others require `complete_user`.
 #[derive(Debug, Default, Serialize)]
 pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -18,10 +246,11 @@ pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
     groups: Option<Vec<Group>>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
     name: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
+    primary_group: Option<Group>,
+
     uid: Option<u32>,
 }
 
@@ -31,76 +260,207 @@ struct Group {
     name: String,
 }
 
+impl From<uzers::Group> for Group {
+    fn from(group: uzers::Group) -> Self {
+        Self {
+            gid: group.gid(),
+            name: group.name().to_string_lossy().into(),
+        }
+    }
+}
+
 impl User {
-    fn complete(&mut self) -> Result<(), SshdCommandError> {
-        let user = match (self.uid, &self.name) {
-            (Some(uid), _) => {
-                let user = get_user_by_uid(uid).ok_or(
+    /// Complete the user from the user database, the user is looked up once
+    /// by uid when known and otherwise by name.
+    fn complete<B: UserBackend>(
+        &mut self,
+        backend: &B,
+        groups_filter: Option<&[String]>,
+        min_group_gid: Option<u32>,
+        unknown_user: UnknownUser,
+    ) -> Result<(), SshdCommandError> {
+        let lookup = match (self.uid, &self.name) {
+            (Some(uid), _) => backend
+                .user_by_uid(uid)
+                .inspect(|user| {
+                    if self.name.is_none() {
+                        self.name = Some(user.name().to_string_lossy().into());
+                    }
+                })
+                .ok_or_else(|| {
                     SshdCommandError::InvalidTokenArgument(
                         Token::UserId,
                         uid.to_string(),
-                    ),
-                )?;
-
-                if self.name.is_none() {
-                    self.name = Some(
-                        user.name()
-                            .to_str()
-                            .expect("Failed to convert username to String")
-                            .to_string(),
-                    );
-                }
-                user
-            }
-            (_, Some(name)) => {
-                let user = get_user_by_name(&name)
-                    .expect("provided user name doesn't exist");
-                self.uid = Some(user.uid());
-                user
-            }
+                    )
+                }),
+            (_, Some(name)) => backend
+                .user_by_name(name)
+                .inspect(|user| self.uid = Some(user.uid()))
+                .ok_or_else(|| {
+                    SshdCommandError::InvalidTokenArgument(
+                        Token::UserName,
+                        name.clone(),
+                    )
+                }),
             _ => {
                 return Err(SshdCommandError::from("Failed to complete user"))
             }
         };
 
-        self.gid = Some(user.primary_group_id());
+        let user = match (lookup, unknown_user) {
+            (Ok(user), _) => user,
+            (Err(_), UnknownUser::Empty) => {
+                self.groups = Some(Vec::new());
+                return Ok(());
+            }
+            (Err(err), UnknownUser::Error) => return Err(err),
+        };
+
+        let gid = user.primary_group_id();
+        self.gid = Some(gid);
+        self.primary_group = backend.group_by_gid(gid).map(Group::from);
 
-        let groups: Vec<Group> = user
-            .groups()
+        let groups: Vec<Group> = backend
+            .user_groups(&user)
             .unwrap_or_else(|| Vec::with_capacity(0))
             .into_iter()
-            .map(|group| Group {
-                gid: group.gid(),
-                name: group
-                    .name()
-                    .to_str()
-                    .expect("Failed to convert group name to String")
-                    .to_string(),
+            .filter(|group| {
+                groups_filter.is_none_or(|filter| {
+                    filter.iter().any(|name| group.name() == name.as_str())
+                }) && min_group_gid.is_none_or(|min| group.gid() >= min)
             })
+            .map(Group::from)
             .collect();
 
         self.groups = Some(groups);
 
         Ok(())
     }
+}
 
-    pub(crate) fn get_current_uid() -> u32 {
-        get_current_uid()
+/// The IPv4 address of an IPv4-mapped IPv6 `addr`, e.g. `::ffff:192.0.2.1`
+/// from a dual-stack sshd.
+const fn ipv4_mapped(addr: IpAddr) -> Option<Ipv4Addr> {
+    match addr {
+        IpAddr::V6(addr) => addr.to_ipv4_mapped(),
+        IpAddr::V4(_) => None,
     }
+}
+
+/// Check `key_type` against [`KEY_TYPES`] when `strict` is set.
+fn check_key_type(
+    token: Token,
+    key_type: String,
+    strict: bool,
+) -> Result<String, SshdCommandError> {
+    if strict && !KEY_TYPES.contains(&key_type.as_str()) {
+        return Err(SshdCommandError::InvalidTokenArgument(token, key_type));
+    }
+
+    Ok(key_type)
+}
+
+/// Check that `fingerprint` has the shape of an OpenSSH fingerprint, either
+/// `SHA256:<base64>` or `MD5:<hex>:<hex>:...`.
+fn check_fingerprint(
+    token: Token,
+    fingerprint: String,
+) -> Result<String, SshdCommandError> {
+    let valid = match fingerprint.split_once(':') {
+        Some(("SHA256", hash)) => {
+            !hash.is_empty()
+                && hash.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || matches!(c, '+' | '/')
+                })
+        }
+        Some(("MD5", hash)) => {
+            hash.split(':').count() == 16
+                && hash.split(':').all(|byte| {
+                    byte.len() == 2
+                        && byte.chars().all(|c| c.is_ascii_hexdigit())
+                })
+        }
+        _ => false,
+    };
 
-    pub(crate) fn get_current_name() -> String {
-        get_current_username()
-            .unwrap_or_else(|| "unknown".into())
-            .to_str()
-            .expect("Failed to convert username to a str")
-            .to_string()
+    if !valid {
+        return Err(SshdCommandError::InvalidTokenArgument(
+            token,
+            fingerprint,
+        ));
     }
+
+    Ok(fingerprint)
+}
+
+/// Decode a base64 encoded key or certificate.
+fn decode_key(token: Token, key: &str) -> Result<Vec<u8>, SshdCommandError> {
+    STANDARD.decode(key).map_err(|_| {
+        SshdCommandError::InvalidTokenArgument(token, key.to_string())
+    })
+}
+
+/// Get the key type from a key or certificate in the SSH wire format, where
+/// it's the first length prefixed string.
+fn embedded_key_type(blob: &[u8]) -> Option<&[u8]> {
+    let (len, rest) = blob.split_first_chunk::<4>()?;
+    let len = usize::try_from(u32::from_be_bytes(*len)).ok()?;
+
+    rest.get(..len)
 }
 
 pub fn build_context<I: Iterator<Item = String>>(
     front_matter: FrontMatter,
+    args: I,
+) -> Result<Context, SshdCommandError> {
+    build_context_with(
+        front_matter,
+        RenderOptions::default(),
+        args,
+        &UzersBackend,
+    )
+}
+
+/// Like [`build_context`], but with the `options` of the caller and the user
+/// database of `backend`.
+///
+/// # Errors
+///
+/// Will return `Err` on invalid arguments or an unknown user.
+#[expect(clippy::too_many_lines)]
+pub fn build_context_with<I: Iterator<Item = String>, B: UserBackend>(
+    front_matter: FrontMatter,
+    options: RenderOptions,
     mut args: I,
+    backend: &B,
 ) -> Result<Context, SshdCommandError> {
+    let debug = options.debug;
+    // Only trace the arguments consumed by the tokens, not the leftover
+    // ones counted by `strict_args`
+    let mut consumed = args.by_ref().inspect(|arg| {
+        debug!(debug, "  argument `{}`", truncate_arg(arg));
+    });
+
+    if front_matter.sshd_command.strict_context {
+        if let Some(key) =
+            front_matter
+                .extra_context
+                .as_object()
+                .and_then(|extra_context| {
+                    extra_context.keys().find(|key| {
+                        is_reserved_context_key(key)
+                            || front_matter.tokens().iter().any(|token| {
+                                matches!(token, Token::Unknown(name) if name == *key)
+                            })
+                    })
+                })
+        {
+            return Err(
+                FrontMatterError::ReservedContextKey(key.clone()).into()
+            );
+        }
+    }
+
     let mut context = match &front_matter.extra_context {
         serde_json::Value::Object(_) => {
             Context::from_serialize(&front_matter.extra_context)?
@@ -108,72 +468,937 @@ pub fn build_context<I: Iterator<Item = String>>(
         _ => Context::new(),
     };
 
+    // Missing arguments fall back to the environment, then to the defaults
+    let fallback = |token: Token| {
+        front_matter
+            .sshd_command
+            .env_fallback
+            .then(|| token.env_var().and_then(|var| env::var(var).ok()))
+            .flatten()
+            .or_else(|| {
+                front_matter.sshd_command.defaults.get(&token).cloned()
+            })
+    };
     let mut user = User::default();
+    let mut auth_key_blob = None;
+    let mut auth_key_type = None;
 
     // Loop over and parse passed command line arguments for given `Token`
     for token in front_matter.sshd_command.tokens() {
+        debug!(debug, "token {token}");
         match token {
             Token::ConnectionEndpoints => {
-                // TODO: report what argument is missing not just the token
-                let client_addr: IpAddr =
-                    next_arg!(args, _, Token::ConnectionEndpoints);
-                let client_port: u16 =
-                    next_arg!(args, _, Token::ConnectionEndpoints);
+                // `%C` is four arguments, or a single one when it's quoted
+                // in sshd_config
+                let first = consumed.next();
+                let joined = first.as_deref().map(|arg| {
+                    arg.split_whitespace()
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                });
+                let mut endpoints = match joined {
+                    Some(fields) if fields.len() == 4 => fields,
+                    Some(fields) if fields.len() > 1 => {
+                        return Err(
+                            SshdCommandError::InvalidConnectionEndpoints(
+                                first.unwrap_or_default(),
+                            ),
+                        );
+                    }
+                    _ => first
+                        .into_iter()
+                        .chain(consumed.by_ref().take(3))
+                        .collect(),
+                }
+                .into_iter();
+
+                let ScopedIpAddr {
+                    addr: client_addr,
+                    scope: client_scope,
+                } = next_arg!(
+                    endpoints,
+                    _,
+                    Token::ConnectionEndpoints,
+                    1,
+                    "client address"
+                );
+                let client_port: u16 = next_arg!(
+                    endpoints,
+                    _,
+                    Token::ConnectionEndpoints,
+                    2,
+                    "client port"
+                );
 
                 let client = SocketAddr::new(client_addr, client_port);
 
-                let server_addr: IpAddr =
-                    next_arg!(args, _, Token::ConnectionEndpoints);
-                let server_port: u16 =
-                    next_arg!(args, _, Token::ConnectionEndpoints);
+                let ScopedIpAddr {
+                    addr: server_addr,
+                    scope: server_scope,
+                } = next_arg!(
+                    endpoints,
+                    _,
+                    Token::ConnectionEndpoints,
+                    3,
+                    "server address"
+                );
+                let server_port: u16 = next_arg!(
+                    endpoints,
+                    _,
+                    Token::ConnectionEndpoints,
+                    4,
+                    "server port"
+                );
 
                 let server = SocketAddr::new(server_addr, server_port);
 
                 context.insert("client", &client);
+                context.insert("client_addr", &client_addr);
+                context.insert("client_port", &client_port);
+                if front_matter.sshd_command.resolve_client_hostname {
+                    let timeout = Duration::from_millis(
+                        front_matter
+                            .sshd_command
+                            .resolve_timeout_ms
+                            .unwrap_or(DEFAULT_RESOLVE_TIMEOUT_MS),
+                    );
+                    if let Some(client_hostname) = backend
+                        .hostname_by_addr(client_addr.to_canonical(), timeout)
+                    {
+                        context.insert("client_hostname", &client_hostname);
+                    }
+                }
+                if let Some(client_addr_v4) = ipv4_mapped(client_addr) {
+                    context.insert("client_addr_v4", &client_addr_v4);
+                }
+                if let Some(client_scope) = client_scope {
+                    context.insert("client_scope", &client_scope);
+                }
                 context.insert("server", &server);
+                context.insert("server_addr", &server_addr);
+                if let Some(server_addr_v4) = ipv4_mapped(server_addr) {
+                    context.insert("server_addr_v4", &server_addr_v4);
+                }
+                context.insert("server_port", &server_port);
+                if let Some(server_scope) = server_scope {
+                    context.insert("server_scope", &server_scope);
+                }
+            }
+            Token::RoutingDomain => {
+                let routing_domain = next_arg!(
+                    consumed,
+                    Token::RoutingDomain;
+                    default fallback(Token::RoutingDomain)
+                );
+                if routing_domain.is_empty() {
+                    return Err(SshdCommandError::InvalidTokenArgument(
+                        Token::RoutingDomain,
+                        routing_domain,
+                    ));
+                }
+                context.insert("routing_domain", &routing_domain);
+            }
+            Token::FingerPrintCaKey => {
+                let ca_fingerprint = check_fingerprint(
+                    Token::FingerPrintCaKey,
+                    next_arg!(
+                        consumed,
+                        Token::FingerPrintCaKey;
+                        default fallback(Token::FingerPrintCaKey)
+                    ),
+                )?;
+                context.insert("ca_fingerprint", &ca_fingerprint);
+            }
+            Token::FingerPrintCaKeyOrCert => {
+                let key_fingerprint = check_fingerprint(
+                    Token::FingerPrintCaKeyOrCert,
+                    next_arg!(
+                        consumed,
+                        Token::FingerPrintCaKeyOrCert;
+                        default fallback(Token::FingerPrintCaKeyOrCert)
+                    ),
+                )?;
+                context.insert("key_fingerprint", &key_fingerprint);
             }
-            Token::RoutingDomain => unimplemented!(),
-            Token::FingerPrintCaKey => unimplemented!(),
-            Token::FingerPrintCaKeyOrCert => unimplemented!(),
             Token::HomeDirUser => {
-                let home_dir = next_arg!(args, Token::UserName);
+                let home_dir = next_arg!(
+                    consumed,
+                    Token::HomeDirUser;
+                    default fallback(Token::HomeDirUser)
+                );
+
+                // A missing home directory is a sign of a misconfigured
+                // account
+                if front_matter.sshd_command.require_home
+                    && !Path::new(&home_dir).is_dir()
+                {
+                    return Err(SshdCommandError::InvalidTokenArgument(
+                        Token::HomeDirUser,
+                        home_dir,
+                    ));
+                }
                 context.insert("home_dir", &home_dir);
             }
             Token::KeyIdCert => {
-                let key_id: u32 = next_arg!(args, _, Token::KeyIdCert);
+                let key_id = next_arg!(
+                    consumed,
+                    Token::KeyIdCert;
+                    default fallback(Token::KeyIdCert)
+                );
+                if let Ok(key_id_num) = key_id.parse::<u64>() {
+                    context.insert("key_id_num", &key_id_num);
+                }
                 context.insert("key_id", &key_id);
             }
-            Token::Base64EncodedCaKey => unimplemented!(),
-            Token::Base64EncodedAuthKeyOrCert => unimplemented!(),
+            Token::Base64EncodedCaKey => {
+                let ca_key_b64 = next_arg!(
+                    consumed,
+                    Token::Base64EncodedCaKey;
+                    default fallback(Token::Base64EncodedCaKey)
+                );
+                let ca_key_len =
+                    decode_key(Token::Base64EncodedCaKey, &ca_key_b64)?.len();
+                context.insert("ca_key_b64", &ca_key_b64);
+                context.insert("ca_key_len", &ca_key_len);
+            }
+            Token::Base64EncodedAuthKeyOrCert => {
+                let auth_key_b64 = next_arg!(
+                    consumed,
+                    Token::Base64EncodedAuthKeyOrCert;
+                    default fallback(Token::Base64EncodedAuthKeyOrCert)
+                );
+                let auth_key = decode_key(
+                    Token::Base64EncodedAuthKeyOrCert,
+                    &auth_key_b64,
+                )?;
+                context.insert("auth_key_b64", &auth_key_b64);
+                context.insert("auth_key_len", &auth_key.len());
+                auth_key_blob = Some(auth_key);
+            }
             Token::CertificateSerialNumber => unimplemented!(),
-            Token::CaKeyType => unimplemented!(),
-            Token::CertKeyType => unimplemented!(),
+            Token::CaKeyType => {
+                let ca_key_type = check_key_type(
+                    Token::CaKeyType,
+                    next_arg!(
+                        consumed,
+                        Token::CaKeyType;
+                        default fallback(Token::CaKeyType)
+                    ),
+                    front_matter.sshd_command.strict_key_types,
+                )?;
+                context.insert("ca_key_type", &ca_key_type);
+            }
+            Token::CertKeyType => {
+                let cert_key_type = check_key_type(
+                    Token::CertKeyType,
+                    next_arg!(
+                        consumed,
+                        Token::CertKeyType;
+                        default fallback(Token::CertKeyType)
+                    ),
+                    front_matter.sshd_command.strict_key_types,
+                )?;
+                context.insert("cert_key_type", &cert_key_type);
+                auth_key_type = Some(cert_key_type);
+            }
             Token::UserId => {
-                let uid: u32 = next_arg!(args, _, Token::UserId);
+                let uid: u32 = next_arg!(
+                    consumed,
+                    _,
+                    Token::UserId;
+                    default fallback(Token::UserId)
+                );
+
+                // Refuse users outside of the allowed uid range, e.g. system
+                // accounts
+                let min_uid =
+                    front_matter.sshd_command.min_uid.unwrap_or(u32::MIN);
+                let max_uid =
+                    front_matter.sshd_command.max_uid.unwrap_or(u32::MAX);
+                if !(min_uid..=max_uid).contains(&uid) {
+                    return Err(SshdCommandError::InvalidTokenArgument(
+                        Token::UserId,
+                        uid.to_string(),
+                    ));
+                }
                 user.uid = Some(uid);
             }
             Token::UserName => {
-                let uname = next_arg!(args, Token::UserName);
+                let uname = next_arg!(
+                    consumed,
+                    Token::UserName;
+                    default fallback(Token::UserName)
+                );
                 user.name = Some(uname);
             }
+            // Consumes no argument
+            Token::LiteralPercent => {}
+            // Inserted under its name, e.g. `Z` for `%Z`
+            Token::Unknown(name) => {
+                let arg = next_arg!(
+                    consumed,
+                    token.clone();
+                    default fallback(token.clone())
+                );
+                context.insert(name.as_str(), &arg);
+            }
+        }
+    }
+
+    // Check that the `%k` key is of the type claimed by `%t`
+    if front_matter.sshd_command.verify_key_type {
+        if let (Some(blob), Some(key_type)) = (auth_key_blob, auth_key_type) {
+            if embedded_key_type(&blob) != Some(key_type.as_bytes()) {
+                return Err(SshdCommandError::InvalidTokenArgument(
+                    Token::CertKeyType,
+                    key_type,
+                ));
+            }
+        }
+    }
+
+    // Leftover arguments mean the tokens don't line up with sshd_config
+    if options.strict_args {
+        let extra = args.count();
+        if extra > 0 {
+            return Err(SshdCommandError::ExtraArguments(extra));
         }
     }
 
     // Add additional context
     if front_matter.sshd_command.complete_user {
-        user.complete()?;
+        user.complete(
+            backend,
+            front_matter.sshd_command.groups_filter.as_deref(),
+            front_matter.sshd_command.min_group_gid,
+            front_matter.sshd_command.unknown_user,
+        )?;
     }
     context.insert("user", &user);
 
-    if front_matter.sshd_command.hostname {
+    if front_matter.sshd_command.hostname_fqdn {
+        let Hostname { short, fqdn } = Hostname::get()?;
+        context.insert("hostname", &short);
+        context.insert("hostname_fqdn", &fqdn);
+    } else if front_matter.sshd_command.hostname {
         context.insert(
             "hostname",
-            hostname::get()
+            &hostname::get()
                 .map_err(|_| "Failed to get hostname")?
-                .to_str()
-                .expect("Failed to convert hostname"),
+                .to_string_lossy(),
+        );
+    }
+
+    // Keys the template relies on, e.g. injected with `extra_context_mut`
+    if let Some(key) = front_matter
+        .sshd_command
+        .required_context
+        .iter()
+        .find(|key| !context.contains_key(key))
+    {
+        return Err(
+            FrontMatterError::MissingRequiredContext(key.clone()).into()
         );
     }
 
+    debug!(
+        debug,
+        "context keys: {}",
+        context
+            .clone()
+            .into_json()
+            .as_object()
+            .map(|context| {
+                context.keys().cloned().collect::<Vec<_>>().join(", ")
+            })
+            .unwrap_or_default()
+    );
+
     Ok(context)
 }
+
+/// Shorten a long argument, e.g. a base64 key, for the debug trace.
+fn truncate_arg(arg: &str) -> String {
+    const MAX_CHARS: usize = 16;
+
+    arg.char_indices().nth(MAX_CHARS).map_or_else(
+        || arg.to_owned(),
+        |(end, _)| format!("{}... ({} bytes)", &arg[..end], arg.len()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::crate_version;
+
+    fn front_matter(sshd_command: &str) -> FrontMatter {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+{sshd_command}
+---
+        ",
+            crate_version()
+        );
+
+        template.parse().unwrap()
+    }
+
+    /// [`UserBackend`] with a fixed user database of a single user, counting
+    /// the user lookups.
+    #[derive(Default)]
+    struct FakeBackend {
+        lookups: Cell<usize>,
+        /// The timeout of the last reverse DNS lookup.
+        resolved: Cell<Option<Duration>>,
+    }
+
+    impl FakeBackend {
+        const UID: u32 = 1000;
+        const NAME: &str = "user";
+
+        fn user(&self) -> uzers::User {
+            self.lookups.set(self.lookups.get() + 1);
+            uzers::User::new(Self::UID, Self::NAME, Self::UID)
+        }
+    }
+
+    impl UserBackend for FakeBackend {
+        fn user_by_uid(&self, uid: u32) -> Option<uzers::User> {
+            let user = self.user();
+            (uid == Self::UID).then_some(user)
+        }
+
+        fn user_by_name(&self, name: &str) -> Option<uzers::User> {
+            let user = self.user();
+            (name == Self::NAME).then_some(user)
+        }
+
+        fn user_groups(
+            &self,
+            user: &uzers::User,
+        ) -> Option<Vec<uzers::Group>> {
+            Some(vec![
+                uzers::Group::new(user.primary_group_id(), Self::NAME),
+                uzers::Group::new(100, "users"),
+            ])
+        }
+
+        fn group_by_gid(&self, gid: u32) -> Option<uzers::Group> {
+            (gid == Self::UID).then(|| uzers::Group::new(gid, Self::NAME))
+        }
+
+        fn hostname_by_addr(
+            &self,
+            addr: IpAddr,
+            timeout: Duration,
+        ) -> Option<String> {
+            self.resolved.set(Some(timeout));
+            (addr == IpAddr::from([192, 0, 2, 10]))
+                .then(|| String::from("client.example.org"))
+        }
+    }
+
+    #[test]
+    fn check_complete_single_lookup() {
+        let backend = FakeBackend::default();
+        let mut user = User {
+            uid: Some(FakeBackend::UID),
+            name: Some(String::from(FakeBackend::NAME)),
+            ..Default::default()
+        };
+
+        user.complete(&backend, None, None, UnknownUser::Error)
+            .unwrap();
+
+        assert_eq!(backend.lookups.get(), 1);
+        assert_eq!(user.gid, Some(FakeBackend::UID));
+        assert_eq!(user.groups.map(|groups| groups.len()), Some(2));
+
+        for mut user in [
+            User {
+                uid: Some(FakeBackend::UID),
+                ..Default::default()
+            },
+            User {
+                name: Some(String::from(FakeBackend::NAME)),
+                ..Default::default()
+            },
+        ] {
+            let backend = FakeBackend::default();
+            user.complete(&backend, None, None, UnknownUser::Error)
+                .unwrap();
+
+            assert_eq!(backend.lookups.get(), 1);
+            assert_eq!(user.uid, Some(FakeBackend::UID));
+            assert_eq!(user.name.as_deref(), Some(FakeBackend::NAME));
+        }
+    }
+
+    #[test]
+    fn check_hostname_resolve() {
+        let qualified = Hostname {
+            short: String::from("host"),
+            fqdn: String::from("host.home.arpa"),
+        };
+
+        assert_eq!(Hostname::resolve("host.home.arpa", ""), qualified);
+        assert_eq!(Hostname::resolve("host.home.arpa.", ""), qualified);
+        assert_eq!(
+            Hostname::resolve(
+                "host",
+                "nameserver ::1\nsearch home.arpa lan\n"
+            ),
+            qualified
+        );
+        assert_eq!(
+            Hostname::resolve("host", "search local\ndomain home.arpa.\n"),
+            qualified
+        );
+        assert_eq!(
+            Hostname::resolve("host.home.arpa", "domain example.com\n"),
+            qualified
+        );
+        assert_eq!(
+            Hostname::resolve("host", "nameserver ::1\n"),
+            Hostname {
+                short: String::from("host"),
+                fqdn: String::from("host"),
+            }
+        );
+    }
+
+    #[test]
+    fn check_complete_unknown_user_name() {
+        let front_matter = front_matter(
+            r"    tokens: '%u'
+    complete_user: true",
+        );
+        let args = [String::from("sshd-command-does-not-exist")];
+
+        let context = build_context_with(
+            front_matter,
+            RenderOptions::default(),
+            args.into_iter(),
+            &FakeBackend::default(),
+        );
+
+        assert!(matches!(
+            context,
+            Err(SshdCommandError::InvalidTokenArgument(Token::UserName, name))
+                if name == "sshd-command-does-not-exist"
+        ));
+    }
+
+    #[test]
+    fn check_connection_endpoints() {
+        let build_context = |args: &[&str]| {
+            build_context_with(
+                front_matter("    tokens: '%C %u'"),
+                RenderOptions::default(),
+                args.iter().copied().map(String::from),
+                &FakeBackend::default(),
+            )
+            .unwrap()
+            .into_json()
+        };
+
+        let separate = build_context(&["::1", "41644", "::1", "22", "user"]);
+        let joined = build_context(&["::1 41644 ::1 22", "user"]);
+
+        assert_eq!(separate, joined);
+        assert_eq!(joined["client"], "[::1]:41644");
+        assert_eq!(joined["server_port"], 22);
+        assert_eq!(joined["user"]["name"], "user");
+    }
+
+    #[test]
+    fn check_connection_endpoints_missing() {
+        let result = build_context_with(
+            front_matter("    tokens: '%C'"),
+            RenderOptions::default(),
+            ["::1 41644 ::1"].map(String::from).into_iter(),
+            &FakeBackend::default(),
+        );
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err,
+            SshdCommandError::InvalidConnectionEndpoints(ref arg)
+                if arg == "::1 41644 ::1"
+        ));
+        assert!(
+            err.to_string()
+                .contains("client_addr client_port server_addr server_port"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn check_missing_home_dir() {
+        let result = build_context_with(
+            front_matter("    tokens: '%u %h'"),
+            RenderOptions::default(),
+            [String::from("user")].into_iter(),
+            &FakeBackend::default(),
+        );
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err,
+            SshdCommandError::MissingTokenArgument(Token::HomeDirUser)
+        ));
+        assert!(err.to_string().contains("%h"), "{err}");
+    }
+
+    #[test]
+    fn check_strict_args() {
+        let build_context = |strict_args| {
+            build_context_with(
+                front_matter("    tokens: '%u'"),
+                RenderOptions {
+                    strict_args,
+                    ..Default::default()
+                },
+                ["user", "extra"].map(String::from).into_iter(),
+                &FakeBackend::default(),
+            )
+        };
+
+        assert!(build_context(false).is_ok());
+        assert!(matches!(
+            build_context(true),
+            Err(SshdCommandError::ExtraArguments(1))
+        ));
+    }
+
+    #[test]
+    fn check_resolve_client_hostname() {
+        let build_context = |resolve: &str, client_addr: &str| {
+            let backend = FakeBackend::default();
+            let context = build_context_with(
+                front_matter(&format!("    tokens: '%C'\n{resolve}")),
+                RenderOptions::default(),
+                [client_addr, "41644", "192.0.2.1", "22"]
+                    .map(String::from)
+                    .into_iter(),
+                &backend,
+            )
+            .unwrap()
+            .into_json();
+
+            (context, backend.resolved.get())
+        };
+        let resolve = "    resolve_client_hostname: true";
+
+        let (context, timeout) = build_context(resolve, "192.0.2.10");
+        assert_eq!(context["client_hostname"], "client.example.org");
+        assert_eq!(timeout, Some(Duration::from_secs(1)));
+
+        // An IPv4-mapped address is looked up as IPv4
+        let (context, _) = build_context(resolve, "::ffff:192.0.2.10");
+        assert_eq!(context["client_hostname"], "client.example.org");
+
+        // Without a hostname the key is left out
+        let (context, timeout) = build_context(
+            &format!("{resolve}\n    resolve_timeout_ms: 50"),
+            "192.0.2.20",
+        );
+        assert!(context.get("client_hostname").is_none());
+        assert_eq!(timeout, Some(Duration::from_millis(50)));
+
+        // Opt-in only
+        let (context, timeout) = build_context("", "192.0.2.10");
+        assert!(context.get("client_hostname").is_none());
+        assert_eq!(timeout, None);
+    }
+
+    #[test]
+    fn check_ipv4_mapped_endpoints() {
+        let args = ["::ffff:192.0.2.10", "41644", "192.0.2.1", "22"]
+            .map(String::from)
+            .into_iter();
+
+        let context = build_context_with(
+            front_matter("    tokens: '%C'"),
+            RenderOptions::default(),
+            args,
+            &FakeBackend::default(),
+        )
+        .unwrap()
+        .into_json();
+
+        assert_eq!(context["client_addr"], "::ffff:192.0.2.10");
+        assert_eq!(context["client_addr_v4"], "192.0.2.10");
+        assert_eq!(context["server_addr"], "192.0.2.1");
+        assert!(context.get("server_addr_v4").is_none());
+    }
+
+    #[test]
+    fn check_strict_context() {
+        let front_matter = |strict_context| {
+            let mut front_matter = front_matter(&format!(
+                r"    tokens: '%u'
+    strict_context: {strict_context}"
+            ));
+            *front_matter.extra_context_mut() =
+                serde_json::json!({"user": "shadowed"});
+            front_matter
+        };
+        let args = || [String::from("user")].into_iter();
+
+        assert!(matches!(
+            build_context(front_matter(true), args()),
+            Err(SshdCommandError::FrontMatter(
+                FrontMatterError::ReservedContextKey(key)
+            )) if key == "user"
+        ));
+        assert!(build_context(front_matter(false), args()).is_ok());
+    }
+
+    #[test]
+    fn check_reserved_context_keys() {
+        for key in ["client_hostname", "key_id_num", "user", "hostname_fqdn"] {
+            assert!(is_reserved_context_key(key), "{key}");
+        }
+        assert!(!is_reserved_context_key("domain"));
+    }
+
+    #[test]
+    fn check_required_context() {
+        let mut front_matter = front_matter(
+            r"    tokens: '%u'
+    required_context: [user, ldap_dn]",
+        );
+        let build_context = |front_matter: &FrontMatter| {
+            build_context_with(
+                front_matter.clone(),
+                RenderOptions::default(),
+                [String::from("user")].into_iter(),
+                &FakeBackend::default(),
+            )
+        };
+
+        assert!(matches!(
+            build_context(&front_matter),
+            Err(SshdCommandError::FrontMatter(
+                FrontMatterError::MissingRequiredContext(key)
+            )) if key == "ldap_dn"
+        ));
+
+        *front_matter.extra_context_mut() =
+            serde_json::json!({"ldap_dn": "uid=user,dc=example,dc=org"});
+        assert!(build_context(&front_matter).is_ok());
+    }
+
+    #[test]
+    fn check_complete_unknown_user_empty() {
+        let front_matter = front_matter(
+            r"    tokens: '%u'
+    complete_user: true
+    unknown_user: empty",
+        );
+        let args = [String::from("sshd-command-does-not-exist")];
+
+        let context = build_context_with(
+            front_matter,
+            RenderOptions::default(),
+            args.into_iter(),
+            &FakeBackend::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            context.into_json()["user"],
+            serde_json::json!({
+                "groups": [],
+                "name": "sshd-command-does-not-exist",
+                "uid": null,
+            })
+        );
+    }
+
+    #[test]
+    fn check_complete_unknown_uid() {
+        let front_matter = front_matter(
+            r"    tokens: '%U'
+    complete_user: true",
+        );
+        let args = [String::from("0")];
+
+        let context = build_context_with(
+            front_matter,
+            RenderOptions::default(),
+            args.into_iter(),
+            &FakeBackend::default(),
+        );
+
+        assert!(matches!(
+            context,
+            Err(SshdCommandError::InvalidTokenArgument(Token::UserId, uid))
+                if uid == "0"
+        ));
+    }
+
+    #[test]
+    fn check_uid_range() {
+        let front_matter = || {
+            front_matter(
+                r"    tokens: '%U'
+    min_uid: 1000
+    max_uid: 59999",
+            )
+        };
+        let build = |uid: &str| {
+            build_context(front_matter(), [String::from(uid)].into_iter())
+        };
+
+        assert!(build("1000").is_ok());
+        assert!(build("59999").is_ok());
+        assert!(matches!(
+            build("0"),
+            Err(SshdCommandError::InvalidTokenArgument(Token::UserId, uid))
+                if uid == "0"
+        ));
+        assert!(matches!(
+            build("65534"),
+            Err(SshdCommandError::InvalidTokenArgument(Token::UserId, _))
+        ));
+    }
+
+    #[test]
+    fn check_require_home() {
+        let home_dir = env::temp_dir().join("sshd-command-require-home");
+        fs::create_dir_all(&home_dir).unwrap();
+        let build = |home_dir: &Path| {
+            build_context(
+                front_matter(
+                    r"    tokens: '%h'
+    require_home: true",
+                ),
+                [home_dir.to_string_lossy().into_owned()].into_iter(),
+            )
+        };
+
+        assert!(build(&home_dir).is_ok());
+        assert!(matches!(
+            build(&home_dir.join("missing")),
+            Err(SshdCommandError::InvalidTokenArgument(
+                Token::HomeDirUser,
+                _
+            ))
+        ));
+
+        // Not a directory
+        let file = home_dir.join("file");
+        fs::write(&file, "").unwrap();
+        assert!(build(&file).is_err());
+    }
+
+    #[test]
+    fn check_complete_primary_group() {
+        let mut user = User {
+            uid: Some(FakeBackend::UID),
+            ..Default::default()
+        };
+
+        user.complete(&FakeBackend::default(), None, None, UnknownUser::Error)
+            .unwrap();
+
+        let primary_group = user.primary_group.unwrap();
+        assert_eq!(primary_group.gid, FakeBackend::UID);
+        assert_eq!(primary_group.name, FakeBackend::NAME);
+    }
+
+    #[test]
+    fn check_user_without_complete() {
+        let context = build_context_with(
+            front_matter("    tokens: '%u'"),
+            RenderOptions::default(),
+            [String::from(FakeBackend::NAME)].into_iter(),
+            &FakeBackend::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            context.into_json()["user"],
+            serde_json::json!({"name": FakeBackend::NAME, "uid": null})
+        );
+
+        let context = build_context_with(
+            front_matter("    tokens: ''"),
+            RenderOptions::default(),
+            std::iter::empty(),
+            &FakeBackend::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            context.into_json()["user"],
+            serde_json::json!({"name": null, "uid": null})
+        );
+    }
+
+    #[test]
+    fn check_complete_user_groups_filter() {
+        let front_matter = front_matter(
+            r"    tokens: '%u'
+    complete_user: true
+    groups_filter: [users]",
+        );
+        let args = [String::from(FakeBackend::NAME)];
+
+        let context = build_context_with(
+            front_matter,
+            RenderOptions::default(),
+            args.into_iter(),
+            &FakeBackend::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            context.into_json()["user"],
+            serde_json::json!({
+                "gid": FakeBackend::UID,
+                "groups": [{"gid": 100, "name": "users"}],
+                "name": FakeBackend::NAME,
+                "primary_group": {
+                    "gid": FakeBackend::UID,
+                    "name": FakeBackend::NAME,
+                },
+                "uid": FakeBackend::UID,
+            })
+        );
+    }
+
+    #[test]
+    fn check_complete_min_group_gid() {
+        let front_matter = front_matter(
+            r"    tokens: '%u'
+    complete_user: true
+    min_group_gid: 1000",
+        );
+        let args = [String::from(FakeBackend::NAME)];
+
+        let context = build_context_with(
+            front_matter,
+            RenderOptions::default(),
+            args.into_iter(),
+            &FakeBackend::default(),
+        )
+        .unwrap();
+
+        // The system group `users` (100) is dropped
+        assert_eq!(
+            context.into_json()["user"]["groups"],
+            serde_json::json!([
+                {"gid": FakeBackend::UID, "name": FakeBackend::NAME},
+            ])
+        );
+    }
+}
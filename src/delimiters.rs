@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+
+use crate::frontmatter::Delimiters;
+
+/// Tera's own `(start, end)` delimiters of variables, blocks and comments.
+const TERA_DELIMITERS: [(&str, &str); 3] =
+    [("{{", "}}"), ("{%", "%}"), ("{#", "#}")];
+
+/// Translate `body` written with custom `delimiters` to Tera's delimiters.
+///
+/// Text outside the custom delimiters is kept literal, even when it contains
+/// Tera's delimiters.
+///
+/// # Errors
+///
+/// Will return `Err` when a custom start delimiter is never closed.
+pub fn to_tera<'a>(
+    body: &'a str,
+    delimiters: &Delimiters,
+) -> tera::Result<Cow<'a, str>> {
+    if *delimiters == Delimiters::default() {
+        return Ok(Cow::Borrowed(body));
+    }
+
+    let custom =
+        [&delimiters.variable, &delimiters.block, &delimiters.comment];
+
+    let mut tera_body = String::with_capacity(body.len());
+    let mut rest = body;
+    loop {
+        // The first custom start delimiter, the longest one on a tie
+        let next = custom
+            .iter()
+            .zip(TERA_DELIMITERS)
+            .filter_map(|((start, end), tera)| {
+                rest.find(start.as_str()).map(|i| (i, start, end, tera))
+            })
+            .min_by_key(|(i, start, ..)| (*i, usize::MAX - start.len()));
+
+        let Some((i, start, end, (tera_start, tera_end))) = next else {
+            push_literal(&mut tera_body, rest);
+            break;
+        };
+
+        push_literal(&mut tera_body, &rest[..i]);
+        let tag = &rest[i + start.len()..];
+        let tag_len = tag.find(end.as_str()).ok_or_else(|| {
+            tera::Error::msg(format!("unclosed delimiter `{start}`"))
+        })?;
+
+        tera_body.push_str(tera_start);
+        tera_body.push_str(&tag[..tag_len]);
+        tera_body.push_str(tera_end);
+        rest = &tag[tag_len + end.len()..];
+    }
+
+    Ok(Cow::Owned(tera_body))
+}
+
+/// Push `text` so Tera renders it as is.
+fn push_literal(tera_body: &mut String, text: &str) {
+    if TERA_DELIMITERS
+        .iter()
+        .any(|(start, _)| text.contains(start))
+    {
+        tera_body.push_str("{% raw %}");
+        tera_body.push_str(text);
+        tera_body.push_str("{% endraw %}");
+    } else {
+        tera_body.push_str(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_to_tera() {
+        let delimiters = Delimiters {
+            variable: (String::from("[["), String::from("]]")),
+            ..Delimiters::default()
+        };
+
+        // Unchanged block delimiters are kept
+        assert_eq!(
+            to_tera("# {{ literal }}\n[[ user.name ]]{% if a %}", &delimiters)
+                .unwrap(),
+            "{% raw %}# {{ literal }}\n{% endraw %}{{ user.name }}{% if a %}"
+        );
+        assert_eq!(
+            to_tera("{%- if a -%}[[-a-]]", &Delimiters::default()).unwrap(),
+            "{%- if a -%}[[-a-]]"
+        );
+        assert_eq!(
+            to_tera("[[- user.name -]]", &delimiters).unwrap(),
+            "{{- user.name -}}"
+        );
+        assert!(to_tera("[[ user.name", &delimiters).is_err());
+    }
+}
@@ -10,12 +10,27 @@ pub enum SshdCommandError {
     #[error("token {0} has missing argument(s)")]
     MissingTokenArgument(Token),
 
+    #[error("token {0} missing argument {1} ({2})")]
+    MissingTokenArgumentAt(Token, usize, &'static str),
+
     #[error("token {0} has invalid argument: {1}")]
     InvalidTokenArgument(Token, String),
 
+    #[error(
+        "token %C has invalid argument `{0}`, expected `client_addr \
+         client_port server_addr server_port`"
+    )]
+    InvalidConnectionEndpoints(String),
+
+    #[error("{0} argument(s) left over after the last token")]
+    ExtraArguments(usize),
+
     #[error("tera")]
     Tera(#[from] tera::Error),
 
+    #[error("output exceeds the maximum of {0} bytes")]
+    OutputTooLarge(usize),
+
     #[error("general error")]
     Unknown(Box<dyn std::error::Error>),
 }
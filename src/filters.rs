@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap, error::Error as StdError, fmt::Write, iter,
+    net::IpAddr,
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use sha2::{Digest, Sha256};
+use tera::{Context, Tera, Value};
+use thiserror::Error;
+
+/// Register the custom filters and functions on `tera`, functions that
+/// depend on the user are bound to the user of `context`.
+pub fn register(tera: &mut Tera, context: &Context) {
+    tera.register_filter("b64decode", b64decode);
+    tera.register_filter("sha256", sha256);
+    tera.register_filter("glob_match", glob_match);
+    tera.register_function("ip_in_cidr", ip_in_cidr);
+
+    let groups = user_groups(context);
+    tera.register_function("has_group", move |args: &HashMap<_, _>| {
+        has_group(groups.as_deref(), args)
+    });
+
+    tera.register_function("deny", deny);
+}
+
+/// The error `deny()` aborts the render with, see [`is_denied`].
+#[derive(Error, Debug)]
+#[error("the user is denied")]
+struct Denied;
+
+/// Deny the user: abort the render with [`Denied`], the caller outputs
+/// nothing instead of the error.
+fn deny(_: &HashMap<String, Value>) -> tera::Result<Value> {
+    Err(tera::Error::chain("deny", Denied))
+}
+
+/// Whether the template `body` may call `deny()`, conservatively by name so
+/// the output of every other template can be streamed.
+pub fn may_deny(body: &str) -> bool {
+    body.contains("deny")
+}
+
+/// Whether the render failed because the template called `deny()`, Tera
+/// wraps the error of a function call.
+pub fn is_denied(err: &tera::Error) -> bool {
+    let err: &(dyn StdError + 'static) = err;
+
+    iter::successors(Some(err), |&err| err.source())
+        .any(<dyn StdError>::is::<Denied>)
+}
+
+/// Names of the groups of the user in `context`, only present when the user
+/// is completed.
+fn user_groups(context: &Context) -> Option<Vec<String>> {
+    let groups = context.get("user")?.get("groups")?.as_array()?;
+
+    Some(
+        groups
+            .iter()
+            .filter_map(|group| group.get("name")?.as_str())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Check if the user is a member of the group `name`.
+fn has_group(
+    groups: Option<&[String]>,
+    args: &HashMap<String, Value>,
+) -> tera::Result<Value> {
+    let groups = groups.ok_or_else(|| {
+        tera::Error::msg("has_group: requires `complete_user: true`")
+    })?;
+    let name = args
+        .get("name")
+        .and_then(Value::as_str)
+        .ok_or_else(|| tera::Error::msg("has_group: expected a `name`"))?;
+
+    Ok(Value::from(groups.iter().any(|group| group == name)))
+}
+
+/// Check if the address `ip` is in the network `cidr`, e.g. `10.0.0.0/8`.
+///
+/// An IPv4-mapped IPv6 address matches IPv4 networks, a network without
+/// prefix length is a single address.
+fn ip_in_cidr(args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let arg = |name| {
+        args.get(name).and_then(Value::as_str).ok_or_else(|| {
+            tera::Error::msg(format!("ip_in_cidr: expected an `{name}`"))
+        })
+    };
+    let ip = arg("ip")?;
+    let cidr = arg("cidr")?;
+
+    let ip: IpAddr = ip.parse().map_err(|_| {
+        tera::Error::msg(format!("ip_in_cidr: invalid address `{ip}`"))
+    })?;
+    let (network, prefix) = parse_cidr(cidr).ok_or_else(|| {
+        tera::Error::msg(format!("ip_in_cidr: invalid network `{cidr}`"))
+    })?;
+
+    let contains = match (ip.to_canonical(), network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    };
+
+    Ok(Value::from(contains))
+}
+
+/// Parse a network `address/prefix` to the address and prefix length.
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let (network, prefix) = cidr
+        .split_once('/')
+        .map_or((cidr, None), |(n, p)| (n, Some(p)));
+    let network: IpAddr = network.parse().ok()?;
+    let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().ok().filter(|p| *p <= max_prefix)?,
+        None => max_prefix,
+    };
+
+    Some((network, prefix))
+}
+
+/// Check if a string matches the shell-style glob `pattern`, e.g. `svc-*`.
+///
+/// `*` matches any characters, `?` a single one, `[a-z]`/`[!a-z]` one (not)
+/// in the class and `\` escapes the next character. An unclosed class, an
+/// inverted range or a trailing `\` is an invalid pattern.
+fn glob_match(
+    value: &Value,
+    args: &HashMap<String, Value>,
+) -> tera::Result<Value> {
+    let value = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("glob_match: expected a string"))?;
+    let pattern = args
+        .get("pattern")
+        .and_then(Value::as_str)
+        .ok_or_else(|| tera::Error::msg("glob_match: expected a `pattern`"))?;
+    let parts = parse_glob(pattern).ok_or_else(|| {
+        tera::Error::msg(format!("glob_match: invalid pattern `{pattern}`"))
+    })?;
+    let chars: Vec<char> = value.chars().collect();
+
+    Ok(Value::from(glob_matches(&parts, &chars)))
+}
+
+/// A part of a glob pattern, matching a single character except for `*`.
+enum GlobPart {
+    Char(char),
+    Any,
+    Star,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl GlobPart {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Char(expected) => *expected == c,
+            Self::Any | Self::Star => true,
+            Self::Class { negated, ranges } => {
+                ranges
+                    .iter()
+                    .any(|(start, end)| (*start..=*end).contains(&c))
+                    != *negated
+            }
+        }
+    }
+}
+
+/// Parse a glob pattern, `None` for an invalid one.
+fn parse_glob(pattern: &str) -> Option<Vec<GlobPart>> {
+    let mut chars = pattern.chars();
+    let mut parts = Vec::new();
+
+    while let Some(c) = chars.next() {
+        parts.push(match c {
+            '*' => GlobPart::Star,
+            '?' => GlobPart::Any,
+            '\\' => GlobPart::Char(chars.next()?),
+            '[' => {
+                let negated = chars.clone().next() == Some('!');
+                if negated {
+                    chars.next();
+                }
+
+                let mut ranges = Vec::new();
+                loop {
+                    // A `]` first in the class is literal
+                    let start = match chars.next()? {
+                        ']' if !ranges.is_empty() => break,
+                        '\\' => chars.next()?,
+                        start => start,
+                    };
+                    let mut lookahead = chars.clone();
+                    let end = if lookahead.next() == Some('-')
+                        && lookahead.next().is_some_and(|c| c != ']')
+                    {
+                        chars.next();
+                        match chars.next()? {
+                            '\\' => chars.next()?,
+                            end => end,
+                        }
+                    } else {
+                        start
+                    };
+                    if start > end {
+                        return None;
+                    }
+                    ranges.push((start, end));
+                }
+
+                GlobPart::Class { negated, ranges }
+            }
+            c => GlobPart::Char(c),
+        });
+    }
+
+    Some(parts)
+}
+
+/// Match `chars` against the glob `parts`, backtracking to the last `*`.
+fn glob_matches(parts: &[GlobPart], chars: &[char]) -> bool {
+    let (mut p, mut c) = (0, 0);
+    // The last `*` and the position in `chars` it matches up to
+    let mut star = None;
+
+    while c < chars.len() {
+        match parts.get(p) {
+            Some(GlobPart::Star) => {
+                star = Some((p, c));
+                p += 1;
+            }
+            Some(part) if part.matches(chars[c]) => {
+                p += 1;
+                c += 1;
+            }
+            _ => match star {
+                Some((star_p, star_c)) => {
+                    star = Some((star_p, star_c + 1));
+                    p = star_p + 1;
+                    c = star_c + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    parts[p..].iter().all(|part| matches!(part, GlobPart::Star))
+}
+
+/// Decode a base64 string to an array of bytes.
+fn b64decode(
+    value: &Value,
+    _: &HashMap<String, Value>,
+) -> tera::Result<Value> {
+    let encoded = value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg("b64decode: expected a string"))?;
+    let bytes = STANDARD
+        .decode(encoded)
+        .map_err(|e| tera::Error::msg(format!("b64decode: {e}")))?;
+
+    Ok(Value::from(bytes))
+}
+
+/// Hash a string or an array of bytes (e.g. from `b64decode`) with SHA-256.
+///
+/// The `format` argument is either `hex` (default) or `openssh`, for a
+/// `SHA256:<base64>` fingerprint like `ssh-keygen -l` prints.
+fn sha256(
+    value: &Value,
+    args: &HashMap<String, Value>,
+) -> tera::Result<Value> {
+    let bytes = match value {
+        Value::String(s) => s.as_bytes().to_vec(),
+        Value::Array(array) => array
+            .iter()
+            .map(|byte| {
+                byte.as_u64()
+                    .and_then(|byte| u8::try_from(byte).ok())
+                    .ok_or_else(|| {
+                        tera::Error::msg("sha256: expected an array of bytes")
+                    })
+            })
+            .collect::<tera::Result<_>>()?,
+        _ => {
+            return Err(tera::Error::msg(
+                "sha256: expected a string or an array of bytes",
+            ))
+        }
+    };
+    let hash = Sha256::digest(bytes);
+
+    match args.get("format").and_then(Value::as_str).unwrap_or("hex") {
+        "hex" => Ok(Value::from(hash.iter().fold(
+            String::with_capacity(hash.len() * 2),
+            |mut hex, byte| {
+                _ = write!(hex, "{byte:02x}");
+                hex
+            },
+        ))),
+        "openssh" => Ok(Value::from(format!(
+            "SHA256:{}",
+            STANDARD_NO_PAD.encode(hash)
+        ))),
+        format => Err(tera::Error::msg(format!(
+            "sha256: unknown format `{format}`, expected `hex` or `openssh`"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_b64decode_sha256() {
+        let no_args = HashMap::new();
+        let openssh =
+            HashMap::from([(String::from("format"), Value::from("openssh"))]);
+
+        let bytes = b64decode(&Value::from("YWJj"), &no_args).unwrap();
+        assert_eq!(bytes, Value::from(b"abc".to_vec()));
+
+        assert_eq!(
+            sha256(&bytes, &no_args).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(&Value::from("abc"), &openssh).unwrap(),
+            "SHA256:ungWv48Bz+pBQUDeXa4iI7ADYaOWF3qctBD/YfIAFa0"
+        );
+
+        assert!(b64decode(&Value::from("not base64!"), &no_args).is_err());
+        assert!(sha256(&Value::from(vec![256]), &no_args).is_err());
+    }
+
+    #[test]
+    fn check_ip_in_cidr() {
+        let check = |ip: &str, cidr: &str| {
+            ip_in_cidr(&HashMap::from([
+                (String::from("ip"), Value::from(ip)),
+                (String::from("cidr"), Value::from(cidr)),
+            ]))
+        };
+
+        // In range
+        assert_eq!(check("10.1.2.3", "10.0.0.0/8").unwrap(), true);
+        assert_eq!(check("::ffff:10.1.2.3", "10.0.0.0/8").unwrap(), true);
+        assert_eq!(check("192.0.2.1", "0.0.0.0/0").unwrap(), true);
+        assert_eq!(check("192.0.2.1", "192.0.2.1").unwrap(), true);
+
+        // Out of range
+        assert_eq!(check("11.0.0.1", "10.0.0.0/8").unwrap(), false);
+        assert_eq!(check("10.0.0.1", "::/0").unwrap(), false);
+
+        // IPv6
+        assert_eq!(check("2001:db8::1", "2001:db8::/32").unwrap(), true);
+        assert_eq!(check("2001:db9::1", "2001:db8::/32").unwrap(), false);
+        assert_eq!(check("fe80::1", "::/0").unwrap(), true);
+
+        assert!(check("10.0.0.1", "10.0.0.0/33").is_err());
+        assert!(check("not an ip", "10.0.0.0/8").is_err());
+    }
+
+    #[test]
+    fn check_glob_match() {
+        let check = |name: &str, pattern: &str| {
+            glob_match(
+                &Value::from(name),
+                &HashMap::from([(
+                    String::from("pattern"),
+                    Value::from(pattern),
+                )]),
+            )
+        };
+
+        // Matching
+        assert_eq!(check("svc-backup", "svc-*").unwrap(), true);
+        assert_eq!(check("svc-", "svc-*").unwrap(), true);
+        assert_eq!(check("user1", "user?").unwrap(), true);
+        assert_eq!(check("user1", "user[0-9]").unwrap(), true);
+        assert_eq!(check("userx", "user[!0-9]").unwrap(), true);
+        assert_eq!(check("a-b-c", "*-*-c").unwrap(), true);
+        assert_eq!(check("*", "\\*").unwrap(), true);
+        assert_eq!(check("]", "[]]").unwrap(), true);
+
+        // Not matching
+        assert_eq!(check("admin", "svc-*").unwrap(), false);
+        assert_eq!(check("svc", "svc-*").unwrap(), false);
+        assert_eq!(check("user10", "user?").unwrap(), false);
+        assert_eq!(check("userx", "user[0-9]").unwrap(), false);
+        assert_eq!(check("user1", "user[!0-9]").unwrap(), false);
+        assert_eq!(check("svc-x", "\\*").unwrap(), false);
+
+        // Invalid patterns
+        assert!(check("user", "user[").is_err());
+        assert!(check("user", "user[z-a]").is_err());
+        assert!(check("user", "user\\").is_err());
+        assert!(glob_match(&Value::from(1), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn check_has_group() {
+        let groups = [String::from("users"), String::from("wheel")];
+        let wheel =
+            HashMap::from([(String::from("name"), Value::from("wheel"))]);
+        let root =
+            HashMap::from([(String::from("name"), Value::from("root"))]);
+
+        assert_eq!(has_group(Some(&groups), &wheel).unwrap(), true);
+        assert_eq!(has_group(Some(&groups), &root).unwrap(), false);
+        assert!(has_group(None, &wheel).is_err());
+        assert!(has_group(Some(&groups), &HashMap::new()).is_err());
+    }
+}
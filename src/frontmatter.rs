@@ -1,21 +1,26 @@
-use std::io::{BufRead, BufReader, Read};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{BufRead, BufReader, Read},
+    str::FromStr,
+};
 
 use semver::{Version, VersionReq};
-use serde::Deserialize;
+use serde::{de::IntoDeserializer, Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
-    crate_version, Command, CommandTrait, KeysCommand, PrincipalCommand,
-    SshdCommandError, Token,
+    crate_version, Command, CommandTrait, GenericCommand, KeysCommand,
+    PrincipalCommand, RenderOptions, SshdCommandError, Token,
 };
 
 #[derive(Error, Debug)]
 pub enum FrontMatterError {
-    #[error("first line must be '---'")]
+    #[error("first non-blank line must be '---' or '+++'")]
     InvalidFirstLine,
 
     #[error(
-        "missing end separator for frontmatter, template does not contain a second '---' line"
+        "missing end separator for frontmatter, template does not contain a second '---' or '+++' line"
     )]
     MissingEndSeparator,
 
@@ -27,88 +32,521 @@ pub enum FrontMatterError {
     #[error("{1} is not a valid token for {0}")]
     UnsupportedToken(Command, Token),
 
+    #[error("unknown token {0}; did you mean {1}?")]
+    UnknownToken(Token, Token),
+
+    #[error("unknown token {0}, accepted with `ignore_unknown_tokens`")]
+    UnknownTokenNotIgnored(Token),
+
+    #[error("{0} requires OpenSSH {1}, but `openssh_min` is {2}")]
+    TokenRequiresOpenssh(Token, OpensshVersion, OpensshVersion),
+
+    #[error("`%U` or `%u` token required for `complete_user = true`")]
+    CompleteUserMissingToken,
+
+    #[error("no front matter document for {0}")]
+    MissingDocument(Command),
+
+    #[error("front matter key `{0}` is reserved for the template context")]
+    ReservedContextKey(String),
+
+    #[error("required context key `{0}` is missing")]
+    MissingRequiredContext(String),
+
+    #[error("front matter exceeds the maximum of {0} bytes")]
+    TooLarge(usize),
+
+    #[error("front matter with comments can't be formatted without them")]
+    FormatComments,
+
     #[error("parse error: {0}")]
     ParseError(Box<dyn std::error::Error>),
 }
 
-#[derive(Deserialize, PartialEq, Eq, Debug, Default)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Debug, Default)]
 pub struct FrontMatter {
+    #[serde(alias = "sshd-command")]
     pub(crate) sshd_command: FrontMatterSshdCommand,
 
     #[serde(flatten)]
     pub(crate) extra_context: serde_json::Value,
 }
 
-#[derive(Deserialize, PartialEq, Eq, Debug, Default)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Debug, Default)]
 #[serde(deny_unknown_fields)]
+#[expect(clippy::struct_excessive_bools)]
 pub struct FrontMatterSshdCommand {
-    command: Command,
-    tokens: FrontMatterTokens,
-    version: VersionReq,
+    pub(crate) command: Command,
+    pub(crate) tokens: FrontMatterTokens,
 
-    #[serde(default = "bool::default")]
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) version: VersionReq,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "complete-user")]
     pub(crate) complete_user: bool,
 
-    #[serde(default = "bool::default")]
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
     pub(crate) hostname: bool,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "hostname-fqdn")]
+    pub(crate) hostname_fqdn: bool,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "strict-key-types")]
+    pub(crate) strict_key_types: bool,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "groups-filter")]
+    pub(crate) groups_filter: Option<Vec<String>>,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "min-group-gid")]
+    pub(crate) min_group_gid: Option<u32>,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "verify-key-type")]
+    pub(crate) verify_key_type: bool,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "resolve-client-hostname")]
+    pub(crate) resolve_client_hostname: bool,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "resolve-timeout-ms")]
+    pub(crate) resolve_timeout_ms: Option<u64>,
+
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        serialize_with = "crate::frontmatter::_serde::serialize_sorted"
+    )]
+    pub(crate) defaults: HashMap<Token, String>,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) output: Output,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "ignore-unknown-tokens")]
+    pub(crate) ignore_unknown_tokens: bool,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "strict-context")]
+    pub(crate) strict_context: bool,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "required-context")]
+    pub(crate) required_context: Vec<String>,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "env-fallback")]
+    pub(crate) env_fallback: bool,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub(crate) delimiters: Delimiters,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "min-uid")]
+    pub(crate) min_uid: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "max-uid")]
+    pub(crate) max_uid: Option<u32>,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "require-home")]
+    pub(crate) require_home: bool,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    #[serde(alias = "normalize-output")]
+    pub(crate) normalize_output: bool,
+
+    #[serde(default = "bool::default", skip_serializing_if = "is_default")]
+    pub(crate) autoescape: bool,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "unknown-user")]
+    pub(crate) unknown_user: UnknownUser,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "max-output-bytes")]
+    pub(crate) max_output_bytes: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[serde(alias = "openssh-min")]
+    pub(crate) openssh_min: Option<OpensshVersion>,
+}
+
+/// Format of the rendered output.
+#[derive(
+    Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    /// The rendered template as is.
+    #[default]
+    Text,
+    /// Every non-empty line as a JSON object, e.g. `{"principal": "..."}`.
+    Jsonl,
+}
+
+/// What `complete_user` does when the user isn't in the user database.
+#[derive(
+    Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownUser {
+    /// Fail the render.
+    #[default]
+    Error,
+    /// Continue with the user without groups, e.g. for users that aren't
+    /// provisioned locally yet.
+    Empty,
+}
+
+/// An OpenSSH release, e.g. `8.3`, a portable suffix like `p1` is ignored.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub struct OpensshVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl OpensshVersion {
+    #[must_use]
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+}
+
+impl Display for OpensshVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for OpensshVersion {
+    type Err = FrontMatterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let version =
+            s.split_once('p').map_or(s, |(version, _portable)| version);
+
+        version
+            .split_once('.')
+            .and_then(|(major, minor)| {
+                Some(Self::new(major.parse().ok()?, minor.parse().ok()?))
+            })
+            .ok_or_else(|| {
+                FrontMatterError::ParseError(
+                    format!("invalid OpenSSH version `{s}`").into(),
+                )
+            })
+    }
+}
+
+/// Tera delimiters of the template body as `(start, end)` pairs, to avoid
+/// clashing with literal `{{`, `{%` or `{#` in the template.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Delimiters {
+    pub variable: (String, String),
+    pub block: (String, String),
+    pub comment: (String, String),
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self {
+            variable: (String::from("{{"), String::from("}}")),
+            block: (String::from("{%"), String::from("%}")),
+            comment: (String::from("{#"), String::from("#}")),
+        }
+    }
 }
 
-#[derive(PartialEq, Eq, Debug, Default)]
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
 pub struct FrontMatterTokens(pub(crate) Box<[Token]>);
 
+/// Options with their default value are left out when serializing.
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+impl FromStr for FrontMatter {
+    type Err = FrontMatterError;
+
+    /// Parse the front matter at the start of `template`, the body after it
+    /// is ignored.
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        Self::parse(&mut BufReader::new(template.as_bytes()))
+    }
+}
+
 impl FrontMatter {
     const SEPARATOR: &'static str = "---";
+    const TOML_SEPARATOR: &'static str = "+++";
+
+    /// Maximum size of a front matter in bytes, so a template without end
+    /// separator isn't read entirely before failing.
+    pub const DEFAULT_MAX_SIZE: usize = 16 * 1024;
 
     #[must_use]
     pub const fn tokens(&self) -> &[Token] {
         self.sshd_command.tokens()
     }
 
+    /// The sshd command the template is for.
+    #[must_use]
+    pub const fn command(&self) -> Command {
+        self.sshd_command.command
+    }
+
+    /// Check if the running version matches the required `version`.
+    ///
     /// # Errors
     ///
-    /// Will return `Err` on invalid front matter.
+    /// Will return `Err` when the version doesn't match.
     ///
     /// # Panics
     ///
     /// Will panic when [`crate_version`] panics.
-    pub fn validate(&self) -> Result<(), FrontMatterError> {
-        // Check if the version is valid
+    pub fn check_version(&self) -> Result<(), FrontMatterError> {
         let version_req = &self.sshd_command.version;
         let crate_version = crate_version();
-        if !version_req.matches(&crate_version) {
+        if !version_req.matches(crate_version) {
             return Err(FrontMatterError::InvalidVersion(
-                crate_version,
+                crate_version.clone(),
                 version_req.clone(),
             ));
         }
 
-        // Check if all tokens are supported by the command
+        Ok(())
+    }
+
+    /// Reject tokens unknown to this version, unless `ignore_unknown_tokens`
+    /// is set.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` on an unknown token, suggesting the known token
+    /// differing only in case if any.
+    pub fn check_unknown_tokens(&self) -> Result<(), FrontMatterError> {
+        if self.sshd_command.ignore_unknown_tokens {
+            return Ok(());
+        }
+
+        self.tokens()
+            .iter()
+            .find(|token| !token.is_known())
+            .map_or(Ok(()), |token| Err(Self::unknown_token_error(token)))
+    }
+
+    /// Warnings about ignored unknown tokens differing only in case from a
+    /// known token, e.g. `%H` for `%h`, which are most likely a typo.
+    #[must_use]
+    pub fn unknown_token_warnings(&self) -> Vec<FrontMatterError> {
+        if !self.sshd_command.ignore_unknown_tokens {
+            return Vec::new();
+        }
+
+        self.tokens()
+            .iter()
+            .filter(|token| token.case_suggestion().is_some())
+            .map(Self::unknown_token_error)
+            .collect()
+    }
+
+    /// Tokens are case-sensitive, but a typo in case is more likely than a
+    /// token unknown to this version.
+    fn unknown_token_error(token: &Token) -> FrontMatterError {
+        token.case_suggestion().map_or_else(
+            || FrontMatterError::UnknownTokenNotIgnored(token.clone()),
+            |suggestion| {
+                FrontMatterError::UnknownToken(token.clone(), suggestion)
+            },
+        )
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` on invalid front matter.
+    ///
+    /// # Panics
+    ///
+    /// Will panic when [`crate_version`] panics.
+    pub fn validate(&self) -> Result<(), FrontMatterError> {
+        self.validate_with(RenderOptions::default())
+    }
+
+    /// Like [`Self::validate`], but e.g. skip the version check with
+    /// [`RenderOptions::ignore_version`] to warn with
+    /// [`Self::check_version`] instead.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` on invalid front matter.
+    ///
+    /// # Panics
+    ///
+    /// Will panic when [`crate_version`] panics.
+    pub fn validate_with(
+        &self,
+        options: RenderOptions,
+    ) -> Result<(), FrontMatterError> {
+        if !options.ignore_version {
+            self.check_version()?;
+        }
+
+        self.check_unknown_tokens()?;
+
+        // Check if all tokens are supported by the command, support of
+        // ignored unknown tokens can't be checked
         let command = self.sshd_command.command;
         let tokens = &self.sshd_command.tokens.0;
+        let known_tokens: Vec<Token> =
+            tokens.iter().filter(|t| t.is_known()).cloned().collect();
+
         let token_validation = match command {
-            Command::Keys => KeysCommand::validate_tokens(tokens),
-            Command::Principals => PrincipalCommand::validate_tokens(tokens),
+            Command::Keys => KeysCommand::validate_tokens(&known_tokens),
+            Command::Principals => {
+                PrincipalCommand::validate_tokens(&known_tokens)
+            }
+            Command::Generic => GenericCommand::validate_tokens(&known_tokens),
         };
 
         token_validation.map_err(|token| {
             FrontMatterError::UnsupportedToken(command, token)
         })?;
 
+        // An older sshd doesn't pass the tokens added after `openssh_min`
+        if let Some(openssh_min) = self.sshd_command.openssh_min {
+            if let Some((token, required)) = tokens.iter().find_map(|token| {
+                token
+                    .openssh_min()
+                    .filter(|&required| required > openssh_min)
+                    .map(|required| (token.clone(), required))
+            }) {
+                return Err(FrontMatterError::TokenRequiresOpenssh(
+                    token,
+                    required,
+                    openssh_min,
+                ));
+            }
+        }
+
         // If complete_user check if the required token(s) are provided
         if self.sshd_command.complete_user.then(|| {
             tokens
                 .iter()
-                .any(|&t| matches!(t, Token::UserId | Token::UserName))
+                .any(|t| matches!(t, Token::UserId | Token::UserName))
         }) == Some(false)
+        {
+            return Err(FrontMatterError::CompleteUserMissingToken);
+        }
+
+        // Defaults are only supported for single argument string tokens
+        if let Some(token) = self.sshd_command.defaults.keys().find(|t| {
+            !matches!(
+                t,
+                Token::RoutingDomain
+                    | Token::FingerPrintCaKey
+                    | Token::FingerPrintCaKeyOrCert
+                    | Token::HomeDirUser
+                    | Token::KeyIdCert
+                    | Token::Base64EncodedCaKey
+                    | Token::Base64EncodedAuthKeyOrCert
+                    | Token::CaKeyType
+                    | Token::CertKeyType
+                    | Token::UserName
+            )
+        }) {
+            return Err(FrontMatterError::ParseError(
+                format!("no default supported for token {token}").into(),
+            ));
+        }
+
+        if let (Some(min_uid), Some(max_uid)) =
+            (self.sshd_command.min_uid, self.sshd_command.max_uid)
+        {
+            if min_uid > max_uid {
+                return Err(FrontMatterError::ParseError(
+                    "`min_uid` must not be greater than `max_uid`".into(),
+                ));
+            }
+        }
+
+        let delimiters = &self.sshd_command.delimiters;
+        if [&delimiters.variable, &delimiters.block, &delimiters.comment]
+            .iter()
+            .any(|(start, end)| start.is_empty() || end.is_empty())
+        {
+            return Err(FrontMatterError::ParseError(
+                "delimiters must not be empty".into(),
+            ));
+        }
+
+        if self.sshd_command.resolve_client_hostname
+            && !tokens.contains(&Token::ConnectionEndpoints)
+        {
+            return Err(FrontMatterError::ParseError(
+                "`%C` token required for `resolve_client_hostname = true`"
+                    .into(),
+            ));
+        }
+
+        // If verify_key_type check if the required tokens are provided
+        if self.sshd_command.verify_key_type
+            && !(tokens.contains(&Token::CertKeyType)
+                && tokens.contains(&Token::Base64EncodedAuthKeyOrCert))
         {
             return Err(FrontMatterError::ParseError(
-                "`%U` or`%u` token required for `complete_user = true`".into(),
+                "`%t` and `%k` tokens required for `verify_key_type = true`"
+                    .into(),
             ));
         }
 
         Ok(())
     }
 
+    /// Custom (non `sshd_command`) front matter values, these are added to
+    /// the template context.
+    #[must_use]
+    pub const fn extra_context(&self) -> &serde_json::Value {
+        &self.extra_context
+    }
+
+    /// Mutable access to the custom front matter values, e.g. to inject
+    /// runtime values before rendering.
+    pub const fn extra_context_mut(&mut self) -> &mut serde_json::Value {
+        &mut self.extra_context
+    }
+
+    /// Total number of positional arguments consumed by the tokens.
+    #[must_use]
+    pub fn arg_count(&self) -> usize {
+        Token::from_sshd_config_order(self.tokens())
+            .into_iter()
+            .map(|(_, arg_count)| arg_count)
+            .sum()
+    }
+
+    /// Build the `sshd_config(5)` line that invokes `program` with
+    /// `template`, followed by the tokens in the order `build_context`
+    /// consumes them. Tokens without argument, i.e. `%%`, are left out since
+    /// sshd would pass them as an argument.
+    #[must_use]
+    pub fn sshd_config(&self, program: &str, template: &str) -> String {
+        let mut line = format!("{} {program} {template}", self.command());
+        for token in self.tokens().iter().filter(|token| token.arg_count() > 0)
+        {
+            line.push(' ');
+            line.push_str(&token.to_string());
+        }
+
+        line
+    }
+
     /// # Errors
     ///
     /// Will return `Err` when failing to parse the provided front matter.
@@ -116,31 +554,317 @@ impl FrontMatter {
     pub fn parse<R: Read>(
         reader: &mut BufReader<R>,
     ) -> Result<Self, FrontMatterError> {
-        let mut buf = String::new();
-        let mut buf_len;
+        Self::parse_with_max_size(reader, Self::DEFAULT_MAX_SIZE)
+    }
+
+    /// Like [`Self::parse`], but with a front matter of at most `max_size`
+    /// bytes instead of [`Self::DEFAULT_MAX_SIZE`].
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when failing to parse the provided front matter or
+    /// when it's larger than `max_size`.
+    pub fn parse_with_max_size<R: Read>(
+        reader: &mut BufReader<R>,
+        max_size: usize,
+    ) -> Result<Self, FrontMatterError> {
+        let mut lines = 0;
+        let separator = Self::parse_start(reader, &mut lines, max_size)?;
+
+        Self::parse_block(reader, separator, &mut lines, max_size)
+    }
+
+    /// Re-emit the front matter of `template` with canonical key order and
+    /// indentation, the rest of the template is kept byte for byte.
+    ///
+    /// Only the first front matter of a template with multiple documents is
+    /// formatted. Options with their default value are left out.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when failing to parse or serialize the front matter,
+    /// or when it has comments, which would be lost.
+    pub fn format(template: &str) -> Result<String, FrontMatterError> {
+        let mut reader = BufReader::new(template.as_bytes());
+        let unread = |reader: &BufReader<&[u8]>| {
+            reader.buffer().len() + reader.get_ref().len()
+        };
+
+        let mut lines = 0;
+        let separator = Self::parse_start(
+            &mut reader,
+            &mut lines,
+            Self::DEFAULT_MAX_SIZE,
+        )?;
+        // Keep anything before the start separator, e.g. a shebang
+        let start_end = template.len() - unread(&reader);
+        let preamble_len = template[..start_end]
+            .trim_end_matches(['\r', '\n'])
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+
+        let front_matter = Self::parse_block(
+            &mut reader,
+            separator,
+            &mut lines,
+            Self::DEFAULT_MAX_SIZE,
+        )?;
+        let body_start = template.len() - unread(&reader);
+        let body = &template[body_start..];
+
+        // A `#` starting the line or following whitespace, this may also
+        // match inside a quoted value, which is refused all the same
+        let has_comments =
+            template[start_end..body_start].lines().any(|line| {
+                line.trim_start().starts_with('#') || line.contains(" #")
+            });
+        if has_comments {
+            return Err(FrontMatterError::FormatComments);
+        }
+
+        let serialized = if separator == Self::TOML_SEPARATOR {
+            toml::to_string(&front_matter)
+                .map_err(|err| FrontMatterError::ParseError(Box::new(err)))?
+        } else {
+            serde_yaml::to_string(&front_matter)
+                .map_err(|err| FrontMatterError::ParseError(Box::new(err)))?
+        };
+
+        Ok(format!(
+            "{}{separator}\n{serialized}{separator}\n{body}",
+            &template[..preamble_len]
+        ))
+    }
+
+    /// A new template for `command` and the space separated `tokens`,
+    /// requiring the running version, with a commented example body.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` on invalid or unsupported tokens.
+    pub fn scaffold(
+        command: Command,
+        tokens: &str,
+    ) -> Result<String, FrontMatterError> {
+        let tokens =
+            FrontMatterTokens::deserialize(tokens.into_deserializer())
+                .map_err(|err: serde::de::value::Error| {
+                    FrontMatterError::ParseError(Box::new(err))
+                })?;
+        let version = VersionReq::parse(&crate_version().to_string())
+            .map_err(|err| FrontMatterError::ParseError(Box::new(err)))?;
+        let front_matter = Self {
+            sshd_command: FrontMatterSshdCommand {
+                command,
+                tokens,
+                version,
+                ..FrontMatterSshdCommand::default()
+            },
+            extra_context: serde_json::Value::Object(serde_json::Map::new()),
+        };
+        front_matter.validate()?;
 
-        // Check if first line is front matter start
-        reader
-            .read_line(&mut buf)
+        let serialized = serde_yaml::to_string(&front_matter)
             .map_err(|err| FrontMatterError::ParseError(Box::new(err)))?;
-        if !buf.trim_end().eq(Self::SEPARATOR) {
-            return Err(FrontMatterError::InvalidFirstLine);
+        // `%U` and `%u` both add `user`
+        let mut context_keys: Vec<&str> = Vec::new();
+        for key in front_matter.tokens().iter().flat_map(Token::context_keys) {
+            if !context_keys.contains(key) {
+                context_keys.push(key);
+            }
+        }
+        let example = if context_keys.contains(&"user") {
+            String::from("{{ user.name }}")
+        } else {
+            context_keys.first().map_or_else(
+                || String::from("example"),
+                |key| format!("{{{{ {key} }}}}"),
+            )
+        };
+
+        let context = if context_keys.is_empty() {
+            String::from("-")
+        } else {
+            context_keys.join(", ")
+        };
+        let line = match command {
+            Command::Keys => "authorized key",
+            Command::Principals => "principal",
+            Command::Generic => "output line",
+        };
+
+        Ok(format!(
+            "{separator}\n{serialized}{separator}\n{{#\n\
+             Context of the tokens: {context}\n\n\
+             Write one {line} per line, e.g.:\n\
+             {example}\n\
+             #}}\n",
+            separator = Self::SEPARATOR,
+        ))
+    }
+
+    /// Parse the front matter document for `command` from a template with
+    /// multiple documents, each a front matter followed by its body. Returns
+    /// the front matter with its body.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` when failing to parse a front matter or when no
+    /// document is for `command`.
+    pub fn parse_for_command<R: Read>(
+        reader: &mut BufReader<R>,
+        command: Command,
+    ) -> Result<(Self, String), FrontMatterError> {
+        let mut lines = 0;
+        let mut separator =
+            Self::parse_start(reader, &mut lines, Self::DEFAULT_MAX_SIZE)?;
+        let mut line = String::new();
+
+        loop {
+            let front_matter = Self::parse_block(
+                reader,
+                separator,
+                &mut lines,
+                Self::DEFAULT_MAX_SIZE,
+            )?;
+
+            // The body ends at the start of the next document
+            let mut body = String::new();
+            let mut next_separator = None;
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).map_err(|err| {
+                    FrontMatterError::ParseError(Box::new(err))
+                })? == 0
+                {
+                    break;
+                }
+                lines += 1;
+
+                next_separator = Self::separator(&line);
+                if next_separator.is_some() {
+                    break;
+                }
+                body.push_str(&line);
+            }
+
+            if front_matter.sshd_command.command == command {
+                return Ok((front_matter, body));
+            }
+            separator = next_separator
+                .ok_or(FrontMatterError::MissingDocument(command))?;
+        }
+    }
+
+    /// The separator `line` consists of, if any.
+    fn separator(line: &str) -> Option<&'static str> {
+        match line.trim_end() {
+            Self::SEPARATOR => Some(Self::SEPARATOR),
+            Self::TOML_SEPARATOR => Some(Self::TOML_SEPARATOR),
+            _ => None,
+        }
+    }
+
+    /// Read up to the first non-blank line after an optional shebang, which
+    /// must be the front matter start, and return the separator, which
+    /// determines the format.
+    fn parse_start<R: Read>(
+        reader: &mut BufReader<R>,
+        lines: &mut usize,
+        max_size: usize,
+    ) -> Result<&'static str, FrontMatterError> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            // A line longer than a front matter can't be a separator
+            let read = reader
+                .by_ref()
+                .take(max_size as u64 + 1)
+                .read_line(&mut line)
+                .map_err(|err| FrontMatterError::ParseError(Box::new(err)))?;
+            if read == 0 {
+                return Err(FrontMatterError::InvalidFirstLine);
+            }
+            if read > max_size {
+                return Err(FrontMatterError::TooLarge(max_size));
+            }
+            *lines += 1;
+
+            // Ignore a leading UTF-8 byte order mark and shebang line, so
+            // templates can be executable
+            let line = if *lines == 1 {
+                let line = line.trim_start_matches('\u{feff}');
+                if line.starts_with("#!") {
+                    continue;
+                }
+                line
+            } else {
+                &line
+            };
+            if !line.trim().is_empty() {
+                return Self::separator(line)
+                    .ok_or(FrontMatterError::InvalidFirstLine);
+            }
         }
+    }
+
+    /// Parse the front matter of at most `max_size` bytes up to the end
+    /// `separator`, `lines` is the number of template lines read so far.
+    fn parse_block<R: Read>(
+        reader: &mut BufReader<R>,
+        separator: &str,
+        lines: &mut usize,
+        max_size: usize,
+    ) -> Result<Self, FrontMatterError> {
+        // Read front matter into `buf` until the end separator, nothing after
+        // it is read. Lines before the front matter are kept as empty lines
+        // so line numbers in errors match the template
+        let mut buf = "\n".repeat(*lines);
+        let mut line = String::new();
+        let mut size = 0;
+        loop {
+            line.clear();
+            // Read at most one byte past `max_size`, even of a single line
+            let limit = (max_size - size) as u64 + 1;
+            let read = reader
+                .by_ref()
+                .take(limit)
+                .read_line(&mut line)
+                .unwrap_or(0);
+            if read == 0 {
+                break;
+            }
+            size += read;
+            if size > max_size {
+                return Err(FrontMatterError::TooLarge(max_size));
+            }
+            *lines += 1;
 
-        // Read front matter into `buf` and verify front matter end is present
-        buf_len = buf.len();
-        while reader.read_line(&mut buf).unwrap_or(0) != 0 {
-            if buf[buf_len..].trim_end().eq(Self::SEPARATOR) {
+            if line.trim_end().eq(separator) {
                 // Reached end of frontmatter
-                let front_matter_str = &buf[..buf_len];
-                let front_matter: Self =
-                    serde_yaml::from_str(front_matter_str).map_err(|err| {
+                let front_matter: Self = if separator == Self::TOML_SEPARATOR {
+                    toml::from_str(&buf).map_err(|err| {
+                        FrontMatterError::ParseError(Box::new(err))
+                    })?
+                } else {
+                    serde_yaml::from_str(&buf).map_err(|err| {
                         FrontMatterError::ParseError(Box::new(err))
-                    })?;
+                    })?
+                };
+
+                front_matter.check_unknown_tokens()?;
 
                 return Ok(front_matter);
             }
-            buf_len = buf.len();
+
+            // Normalize CRLF line endings
+            match line.strip_suffix("\r\n") {
+                Some(line) => {
+                    buf.push_str(line);
+                    buf.push('\n');
+                }
+                None => buf.push_str(&line),
+            }
         }
 
         Err(FrontMatterError::MissingEndSeparator)
@@ -162,9 +886,11 @@ impl From<FrontMatterError> for SshdCommandError {
 mod _serde {
     use core::fmt;
 
-    use serde::{de::Visitor, Deserialize};
+    use std::collections::{BTreeMap, HashMap};
 
-    use super::{FrontMatterTokens, Token};
+    use serde::{de::Visitor, Deserialize, Serialize, Serializer};
+
+    use super::{FrontMatterTokens, OpensshVersion, Token};
 
     struct FrontMatterTokensVisitor;
 
@@ -179,8 +905,17 @@ mod _serde {
         where
             E: serde::de::Error,
         {
-            let tokens: Result<Box<[Token]>, _> =
-                v.split_whitespace().map(Token::try_from).collect();
+            // Ignore a trailing comment, no token contains a `#`
+            let v = v.split_once('#').map_or(v, |(tokens, _comment)| tokens);
+            // Unknown tokens are only accepted with `ignore_unknown_tokens`,
+            // which is checked once the whole front matter is parsed
+            let tokens: Result<Box<[Token]>, _> = v
+                .split_whitespace()
+                .map(|token| {
+                    Token::try_from(token)
+                        .or_else(|token| Token::unknown(token).ok_or(token))
+                })
+                .collect();
 
             tokens.map_or_else(
                 |invalid_token| {
@@ -190,41 +925,168 @@ mod _serde {
                         &self,
                     ))
                 },
-                |tokens| Ok(tokens),
+                |tokens: Box<[Token]>| {
+                    // Every token consumes its own argument(s), a repeated
+                    // token would shift the arguments of all tokens after it
+                    for (i, token) in tokens.iter().enumerate() {
+                        if tokens[..i].contains(token) {
+                            return Err(serde::de::Error::custom(format!(
+                                "duplicate token {token}"
+                            )));
+                        }
+                    }
+
+                    Ok(tokens)
+                },
             )
         }
     }
 
-    impl<'de> Deserialize<'de> for FrontMatterTokens {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    struct TokenVisitor;
+
+    impl Visitor<'_> for TokenVisitor {
+        type Value = Token;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a sshd_config token, see sshd_config(5) for all valid tokens.")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
         where
-            D: serde::Deserializer<'de>,
+            E: serde::de::Error,
         {
-            Ok(Self(
-                deserializer.deserialize_str(FrontMatterTokensVisitor)?,
-            ))
+            Token::try_from(v).map_err(|invalid_token| {
+                serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Str(invalid_token),
+                    &self,
+                )
+            })
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use core::panic;
-    use std::str::FromStr;
+    struct OpensshVersionVisitor;
 
-    use super::*;
+    impl Visitor<'_> for OpensshVersionVisitor {
+        type Value = OpensshVersion;
 
-    fn update_version(
-        version: &Version,
-        delta_major: i64,
-        delta_minor: i64,
-        delta_patch: i64,
-    ) -> Option<Version> {
-        let major = version.major.checked_add_signed(delta_major)?;
-        let minor = version.minor.checked_add_signed(delta_minor)?;
-        let patch = version.patch.checked_add_signed(delta_patch)?;
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an OpenSSH version, e.g. '8.3'")
+        }
 
-        Some(Version::new(major, minor, patch))
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            v.parse().map_err(|_| {
+                serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Str(v),
+                    &self,
+                )
+            })
+        }
+
+        // An unquoted `8.10` is the float 8.1 in YAML and TOML
+        fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Err(E::custom(format!(
+                "OpenSSH version {v} must be quoted, e.g. '8.10'"
+            )))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for OpensshVersion {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_any(OpensshVersionVisitor)
+        }
+    }
+
+    impl Serialize for OpensshVersion {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Token {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_str(TokenVisitor)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for FrontMatterTokens {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            Ok(Self(
+                deserializer.deserialize_str(FrontMatterTokensVisitor)?,
+            ))
+        }
+    }
+
+    impl Serialize for Token {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.collect_str(self)
+        }
+    }
+
+    impl Serialize for FrontMatterTokens {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let tokens: Vec<String> =
+                self.0.iter().map(ToString::to_string).collect();
+            serializer.serialize_str(&tokens.join(" "))
+        }
+    }
+
+    /// Serialize `map` sorted by token, so the output is stable.
+    pub fn serialize_sorted<S>(
+        map: &HashMap<Token, String>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let sorted: BTreeMap<String, &String> = map
+            .iter()
+            .map(|(token, value)| (token.to_string(), value))
+            .collect();
+        sorted.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::panic;
+
+    use super::*;
+
+    fn update_version(
+        version: &Version,
+        delta_major: i64,
+        delta_minor: i64,
+        delta_patch: i64,
+    ) -> Option<Version> {
+        let major = version.major.checked_add_signed(delta_major)?;
+        let minor = version.minor.checked_add_signed(delta_minor)?;
+        let patch = version.patch.checked_add_signed(delta_patch)?;
+
+        Some(Version::new(major, minor, patch))
     }
 
     #[test]
@@ -240,8 +1102,7 @@ sshd_command:
             crate_version()
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(&template);
 
         assert!(front_matter.is_ok());
         let front_matter = front_matter.unwrap();
@@ -258,6 +1119,28 @@ sshd_command:
                     .expect("Failed to parse crate version as `VersionReq`"),
                 complete_user: false,
                 hostname: false,
+                hostname_fqdn: false,
+                strict_key_types: false,
+                groups_filter: None,
+                min_group_gid: None,
+                verify_key_type: false,
+                defaults: HashMap::new(),
+                output: Output::Text,
+                ignore_unknown_tokens: false,
+                strict_context: false,
+                env_fallback: false,
+                delimiters: Delimiters::default(),
+                min_uid: None,
+                max_uid: None,
+                require_home: false,
+                normalize_output: false,
+                autoescape: false,
+                unknown_user: UnknownUser::Error,
+                required_context: vec![],
+                resolve_client_hostname: false,
+                resolve_timeout_ms: None,
+                openssh_min: None,
+                max_output_bytes: None,
             },
             extra_context: serde_json::Value::Object(serde_json::Map::new()),
         };
@@ -265,19 +1148,468 @@ sshd_command:
     }
 
     #[test]
-    fn check_parse_full() {
+    fn check_parse_full() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+    complete_user: true
+    hostname: true
+search_domains:
+    - home.arpa
+    - local
+---
+        ",
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template);
+
+        assert!(front_matter.is_ok());
+        let front_matter = front_matter.unwrap();
+        assert!(front_matter.validate().is_ok());
+
+        let front_matter_expected = FrontMatter {
+            sshd_command: FrontMatterSshdCommand {
+                command: Command::Principals,
+                tokens: FrontMatterTokens(Box::new([
+                    Token::UserId,
+                    Token::UserName,
+                ])),
+                version: VersionReq::parse(&crate_version().to_string())
+                    .expect("Failed to parse crate version as `VersionReq`"),
+                complete_user: true,
+                hostname: true,
+                hostname_fqdn: false,
+                strict_key_types: false,
+                groups_filter: None,
+                min_group_gid: None,
+                verify_key_type: false,
+                defaults: HashMap::new(),
+                output: Output::Text,
+                ignore_unknown_tokens: false,
+                strict_context: false,
+                env_fallback: false,
+                delimiters: Delimiters::default(),
+                min_uid: None,
+                max_uid: None,
+                require_home: false,
+                normalize_output: false,
+                autoescape: false,
+                unknown_user: UnknownUser::Error,
+                required_context: vec![],
+                resolve_client_hostname: false,
+                resolve_timeout_ms: None,
+                openssh_min: None,
+                max_output_bytes: None,
+            },
+            extra_context: serde_json::json!({"search_domains": ["home.arpa", "local"]}),
+        };
+        assert_eq!(front_matter, front_matter_expected);
+    }
+
+    #[test]
+    fn check_parse_json() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+    complete_user: true
+    hostname: true
+search_domains:
+    - home.arpa
+    - local
+---
+        ",
+            crate_version()
+        );
+        let template_json = format!(
+            r#"---
+{{
+  "sshd_command": {{
+    "version": "{}",
+    "command": "principals",
+    "tokens": "%U %u",
+    "complete_user": true,
+    "hostname": true
+  }},
+  "search_domains": [
+    "home.arpa",
+    "local"
+  ]
+}}
+---
+        "#,
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        let front_matter_json = FrontMatter::from_str(&template_json).unwrap();
+        assert_eq!(front_matter, front_matter_json);
+    }
+
+    #[test]
+    fn check_parse_groups_filter() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+    complete_user: true
+    groups_filter: ['wheel', 'sudo']
+---
+        ",
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        assert_eq!(
+            front_matter.sshd_command.groups_filter,
+            Some(vec![String::from("wheel"), String::from("sudo")])
+        );
+    }
+
+    #[test]
+    fn check_parse_toml() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+    complete_user: true
+    hostname: true
+search_domains:
+    - home.arpa
+    - local
+---
+        ",
+            crate_version()
+        );
+        let template_toml = format!(
+            r#"+++
+search_domains = ["home.arpa", "local"]
+
+[sshd_command]
+version = "{}"
+command = "principals"
+tokens = "%U %u"
+complete_user = true
+hostname = true
++++
+        "#,
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        let front_matter_toml = FrontMatter::from_str(&template_toml).unwrap();
+        assert_eq!(front_matter, front_matter_toml);
+    }
+
+    #[test]
+    fn check_parse_toml_mismatched_separator() {
+        let template = format!(
+            r#"+++
+[sshd_command]
+version = "{}"
+command = "principals"
+tokens = "%U %u"
+---
+        "#,
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template);
+
+        assert!(matches!(
+            front_matter,
+            Err(FrontMatterError::MissingEndSeparator)
+        ));
+    }
+
+    #[test]
+    fn check_parse_crlf() {
+        let template = format!(
+            "---\r\nsshd_command:\r\n    version: {}\r\n    command: principals\r\n    tokens: '%U %u'\r\nname: |\r\n    multi\r\n    line\r\n---\r\n",
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        assert_eq!(
+            front_matter.extra_context,
+            serde_json::json!({"name": "multi\nline\n"})
+        );
+    }
+
+    #[test]
+    fn check_parse_bom() {
+        let template = format!(
+            "\u{feff}---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+---
+        ",
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template);
+
+        assert!(front_matter.is_ok());
+    }
+
+    #[test]
+    fn check_extra_context_mut() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+search_domains:
+    - local
+---
+        ",
+            crate_version()
+        );
+
+        let mut front_matter = FrontMatter::from_str(&template).unwrap();
+
+        front_matter.extra_context_mut()["ldap_dn"] =
+            serde_json::json!("uid=user,dc=example");
+
+        assert_eq!(
+            front_matter.extra_context(),
+            &serde_json::json!({
+                "search_domains": ["local"],
+                "ldap_dn": "uid=user,dc=example",
+            })
+        );
+    }
+
+    #[test]
+    fn check_parse_defaults() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u %D'
+    defaults:
+        '%D': rdomain0
+---
+        ",
+            crate_version()
+        );
+
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        assert!(front_matter.validate().is_ok());
+        assert_eq!(
+            front_matter.sshd_command.defaults,
+            HashMap::from([(Token::RoutingDomain, String::from("rdomain0"))])
+        );
+    }
+
+    #[test]
+    fn check_validate_unsupported_default() {
+        for token in [
+            Token::ConnectionEndpoints,
+            Token::CertificateSerialNumber,
+            Token::UserId,
+            Token::LiteralPercent,
+            Token::Unknown(String::from("Z")),
+        ] {
+            let mut front_matter = FrontMatter::default();
+            front_matter.sshd_command.ignore_unknown_tokens = true;
+            front_matter
+                .sshd_command
+                .defaults
+                .insert(token, String::from("value"));
+
+            assert!(matches!(
+                front_matter.validate(),
+                Err(FrontMatterError::ParseError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn check_validate_unknown_tokens() {
+        let template = |ignore| {
+            format!(
+                r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u %Z'
+    ignore_unknown_tokens: {ignore}
+---
+        ",
+                crate_version()
+            )
+        };
+
+        let err = FrontMatter::from_str(&template(false)).unwrap_err();
+        assert!(matches!(
+            err,
+            FrontMatterError::UnknownTokenNotIgnored(Token::Unknown(ref name))
+                if name == "Z"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "unknown token %Z, accepted with `ignore_unknown_tokens`"
+        );
+
+        let front_matter = FrontMatter::from_str(&template(true)).unwrap();
+        assert_eq!(
+            front_matter.tokens(),
+            [Token::UserName, Token::Unknown(String::from("Z"))]
+        );
+        assert!(front_matter.validate().is_ok());
+    }
+
+    #[test]
+    fn check_validate_unknown_tokens_generic() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: generic
+    tokens: '%u %Z'
+    ignore_unknown_tokens: true
+---
+        ",
+            crate_version()
+        );
+
+        let mut front_matter = FrontMatter::from_str(&template).unwrap();
+        assert!(front_matter.validate().is_ok());
+
+        front_matter.sshd_command.ignore_unknown_tokens = false;
+        assert!(matches!(
+            front_matter.validate(),
+            Err(FrontMatterError::UnknownTokenNotIgnored(Token::Unknown(_)))
+        ));
+    }
+
+    #[test]
+    fn check_parse_error_line() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    complete_user: maybe
+    tokens: '%u'
+---
+        ",
+            crate_version()
+        );
+
+        let err = FrontMatter::from_str(&template).unwrap_err();
+        assert!(err.to_string().contains("line 5 column"), "{err}");
+
+        let template = format!(
+            r"+++
+[sshd_command]
+version = '{}'
+command = 'principals'
+complete_user = 'maybe'
+tokens = '%u'
++++
+        ",
+            crate_version()
+        );
+
+        let err = FrontMatter::from_str(&template).unwrap_err();
+        assert!(err.to_string().contains("line 5, column"), "{err}");
+    }
+
+    #[test]
+    fn check_parse_for_command() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {0}
+    command: keys
+    tokens: '%u'
+---
+keys
+---
+sshd_command:
+    version: {0}
+    command: principals
+    tokens: '%U'
+---
+principals
+",
+            crate_version()
+        );
+
+        let (front_matter, body) = FrontMatter::parse_for_command(
+            &mut BufReader::new(template.as_bytes()),
+            Command::Keys,
+        )
+        .unwrap();
+        assert_eq!(front_matter.command(), Command::Keys);
+        assert_eq!(front_matter.tokens(), [Token::UserName]);
+        assert_eq!(body, "keys\n");
+
+        let (front_matter, body) = FrontMatter::parse_for_command(
+            &mut BufReader::new(template.as_bytes()),
+            Command::Principals,
+        )
+        .unwrap();
+        assert_eq!(front_matter.command(), Command::Principals);
+        assert_eq!(front_matter.tokens(), [Token::UserId]);
+        assert_eq!(body, "principals\n");
+
+        let template = template.rsplit_once("---\nsshd").unwrap().0;
+        assert!(matches!(
+            FrontMatter::parse_for_command(
+                &mut BufReader::new(template.as_bytes()),
+                Command::Principals,
+            ),
+            Err(FrontMatterError::MissingDocument(Command::Principals))
+        ));
+    }
+
+    #[test]
+    fn check_parse_without_version() {
+        let template = r"---
+sshd_command:
+    command: principals
+    tokens: '%u'
+---
+";
+
+        let front_matter = FrontMatter::from_str(template).unwrap();
+
+        assert_eq!(front_matter.sshd_command.version, VersionReq::STAR);
+        assert!(front_matter.validate().is_ok());
+    }
+
+    #[test]
+    fn check_parse_next_line() {
         let template = format!(
             r"---
 sshd_command:
     version: {}
     command: principals
     tokens: '%U %u'
-    complete_user: true
-    hostname: true
-search_domains:
-    - home.arpa
-    - local
 ---
+next-line
         ",
             crate_version()
         );
@@ -286,95 +1618,188 @@ search_domains:
         let front_matter = FrontMatter::parse(&mut reader);
 
         assert!(front_matter.is_ok());
-        let front_matter = front_matter.unwrap();
-        assert!(front_matter.validate().is_ok());
 
-        let front_matter_expected = FrontMatter {
-            sshd_command: FrontMatterSshdCommand {
-                command: Command::Principals,
-                tokens: FrontMatterTokens(Box::new([
-                    Token::UserId,
-                    Token::UserName,
-                ])),
-                version: VersionReq::parse(&crate_version().to_string())
-                    .expect("Failed to parse crate version as `VersionReq`"),
-                complete_user: true,
-                hostname: true,
-            },
-            extra_context: serde_json::json!({"search_domains": ["home.arpa", "local"]}),
-        };
-        assert_eq!(front_matter, front_matter_expected);
+        let next_line = reader.lines().next();
+        assert!(matches!(next_line, Some(Ok(_))));
+
+        let next_line = next_line.unwrap().unwrap();
+        assert_eq!(next_line, "next-line");
     }
 
     #[test]
-    fn check_parse_json() {
+    fn check_serialize_round_trip() {
         let template = format!(
             r"---
 sshd_command:
-    version: {}
+    version: '>={}'
     command: principals
-    tokens: '%U %u'
+    tokens: '%U %u %k'
     complete_user: true
-    hostname: true
-search_domains:
-    - home.arpa
-    - local
+    groups_filter: [users]
+    defaults:
+        '%k': AAAA
+    output: jsonl
+    delimiters:
+        variable: ['[[', ']]']
+    min_uid: 1000
+search_domains: [home.arpa]
 ---
-        ",
+",
             crate_version()
         );
-        let template_json = format!(
-            r#"---
-{{
-  "sshd_command": {{
-    "version": "{}",
-    "command": "principals",
-    "tokens": "%U %u",
-    "complete_user": true,
-    "hostname": true
-  }},
-  "search_domains": [
-    "home.arpa",
-    "local"
-  ]
-}}
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        let yaml = serde_yaml::to_string(&front_matter).unwrap();
+        let round_trip =
+            FrontMatter::from_str(&format!("---\n{yaml}---\n")).unwrap();
+
+        assert_eq!(round_trip, front_matter);
+        assert!(yaml.contains("tokens: '%U %u %k'"), "{yaml}");
+        assert!(!yaml.contains("hostname"), "{yaml}");
+    }
+
+    #[test]
+    fn check_scaffold() {
+        let template =
+            FrontMatter::scaffold(Command::Principals, "%U %u").unwrap();
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        assert!(front_matter.validate().is_ok());
+        assert_eq!(front_matter.command(), Command::Principals);
+        assert_eq!(front_matter.tokens(), [Token::UserId, Token::UserName]);
+        assert!(template.contains("{{ user.name }}"), "{template}");
+
+        assert!(matches!(
+            FrontMatter::scaffold(Command::Keys, "%i"),
+            Err(FrontMatterError::UnsupportedToken(
+                Command::Keys,
+                Token::KeyIdCert
+            ))
+        ));
+        assert!(FrontMatter::scaffold(Command::Keys, "%u %u").is_err());
+    }
+
+    #[test]
+    fn check_format() {
+        let template = "#!/usr/bin/sshd-command
 ---
-        "#,
-            crate_version()
+domain: home.arpa
+sshd_command:
+        tokens: '%u'
+        command: principals
+        complete_user: false
+---
+{{ user.name }}@{{ domain }}  \n\n";
+
+        assert_eq!(
+            FrontMatter::format(template).unwrap(),
+            "#!/usr/bin/sshd-command
+---
+sshd_command:
+  command: principals
+  tokens: '%u'
+domain: home.arpa
+---
+{{ user.name }}@{{ domain }}  \n\n"
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader).unwrap();
+        let template = "+++
+sshd_command = { tokens = '%u', command = 'keys' }
++++
+{{ user.name }}";
+
+        assert_eq!(
+            FrontMatter::format(template).unwrap(),
+            "+++
+[sshd_command]
+command = \"keys\"
+tokens = \"%u\"
++++
+{{ user.name }}"
+        );
+    }
 
-        let mut reader = BufReader::new(template_json.as_bytes());
-        let front_matter_json = FrontMatter::parse(&mut reader).unwrap();
-        assert_eq!(front_matter, front_matter_json);
+    #[test]
+    fn check_format_comments() {
+        for template in [
+            "---
+# Principals of the user
+sshd_command:
+    tokens: '%u'
+    command: principals
+---
+{{ user.name }}",
+            "---
+sshd_command:
+    tokens: '%u' # the user
+    command: principals
+---
+{{ user.name }}",
+        ] {
+            assert!(matches!(
+                FrontMatter::format(template),
+                Err(FrontMatterError::FormatComments)
+            ));
+        }
     }
 
     #[test]
-    fn check_parse_next_line() {
-        let template = format!(
+    fn check_parse_tokens_comment() {
+        let front_matter = FrontMatter::from_str(
             r"---
 sshd_command:
-    version: {}
     command: principals
-    tokens: '%U %u'
+    tokens: '%U %u # uid and name'
 ---
-next-line
-        ",
-            crate_version()
-        );
+",
+        )
+        .unwrap();
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        assert_eq!(front_matter.tokens(), [Token::UserId, Token::UserName]);
+    }
 
-        assert!(front_matter.is_ok());
+    #[test]
+    fn check_parse_duplicate_token() {
+        let err = FrontMatter::from_str(
+            r"---
+sshd_command:
+    command: principals
+    tokens: '%u %U %u'
+---
+",
+        )
+        .unwrap_err();
 
-        let next_line = reader.lines().next();
-        assert!(matches!(next_line, Some(Ok(_))));
+        let FrontMatterError::ParseError(source) = err else {
+            panic!("expected a parse error, got {err:?}");
+        };
+        assert!(source.to_string().contains("duplicate token %u"));
+    }
 
-        let next_line = next_line.unwrap().unwrap();
-        assert_eq!(next_line, "next-line");
+    #[test]
+    fn check_ignore_version() {
+        let front_matter = FrontMatter::from_str(
+            r"---
+sshd_command:
+    version: '>=999'
+    command: keys
+    tokens: '%u'
+---
+",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            front_matter.validate(),
+            Err(FrontMatterError::InvalidVersion(..))
+        ));
+
+        let options = RenderOptions {
+            ignore_version: true,
+            ..Default::default()
+        };
+        assert!(front_matter.validate_with(options).is_ok());
+        assert!(front_matter.check_version().is_err());
     }
 
     #[test]
@@ -390,8 +1815,7 @@ sshd_command:
             crate_version()
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(&template);
 
         assert!(matches!(
             front_matter,
@@ -399,6 +1823,52 @@ sshd_command:
         ));
     }
 
+    #[test]
+    fn check_parse_leading_blank_lines() {
+        let template = "\n  \n---
+sshd_command:
+    command: keys
+    tokens: '%u'
+---
+{{ user.name }}";
+
+        let mut reader = BufReader::new(template.as_bytes());
+        let front_matter = FrontMatter::parse(&mut reader).unwrap();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+
+        assert_eq!(front_matter.command(), Command::Keys);
+        assert_eq!(body, "{{ user.name }}");
+    }
+
+    #[test]
+    fn check_parse_shebang() {
+        let template = "#!/usr/bin/sshd-command
+---
+sshd_command:
+    command: keys
+    tokens: '%u'
+---
+{{ user.name }}";
+
+        let mut reader = BufReader::new(template.as_bytes());
+        let front_matter = FrontMatter::parse(&mut reader).unwrap();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+
+        assert_eq!(front_matter.command(), Command::Keys);
+        assert_eq!(body, "{{ user.name }}");
+
+        // Only the first line may be a shebang
+        let mut reader =
+            BufReader::new(&b"\n#!/usr/bin/sshd-command\n---\n"[..]);
+
+        assert!(matches!(
+            FrontMatter::parse(&mut reader),
+            Err(FrontMatterError::InvalidFirstLine)
+        ));
+    }
+
     #[test]
     fn check_parse_missing_end_separator() {
         let template = format!(
@@ -411,8 +1881,7 @@ sshd_command:
             crate_version()
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(&template);
 
         assert!(matches!(
             front_matter,
@@ -433,8 +1902,7 @@ sshd_command:
             crate_version()
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(&template);
 
         assert!(matches!(front_matter, Err(FrontMatterError::ParseError(_))));
 
@@ -442,6 +1910,191 @@ sshd_command:
         assert!(error.contains("%invalid"));
     }
 
+    #[test]
+    fn check_sshd_config_literal_percent() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u %% %C %U'
+---
+        ",
+            crate_version()
+        );
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+
+        let line = front_matter.sshd_config("/bin/sshd-command", "t.tera");
+        let (_, tokens) = line.split_once(" t.tera ").unwrap();
+        assert_eq!(tokens, "%u %C %U");
+
+        // sshd expands the tokens into exactly the arguments consumed
+        let args = tokens.split(' ').flat_map(|token| {
+            Token::try_from(token).unwrap().arg_example().split(' ')
+        });
+        assert_eq!(args.count(), front_matter.arg_count());
+    }
+
+    #[test]
+    fn check_validate_openssh_min() {
+        let front_matter = |openssh_min: &str| {
+            let template = format!(
+                r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%D %u'
+    openssh_min: {openssh_min}
+---
+        ",
+                crate_version()
+            );
+
+            FrontMatter::from_str(&template).unwrap()
+        };
+
+        assert!(matches!(
+            front_matter("'7.4'").validate(),
+            Err(FrontMatterError::TokenRequiresOpenssh(
+                Token::RoutingDomain,
+                _,
+                OpensshVersion { major: 7, minor: 4 }
+            ))
+        ));
+        assert!(front_matter("'8.3'").validate().is_ok());
+        assert!(front_matter("'9.6p1'").validate().is_ok());
+    }
+
+    #[test]
+    fn check_parse_openssh_min_float() {
+        for openssh_min in ["9.0", "8.10"] {
+            let template = format!(
+                r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u'
+    openssh_min: {openssh_min}
+---
+        ",
+                crate_version()
+            );
+
+            let err = FrontMatter::from_str(&template).unwrap_err();
+            assert!(err.to_string().contains("must be quoted"), "{err}");
+        }
+
+        assert_eq!(
+            "8.10".parse::<OpensshVersion>().unwrap(),
+            OpensshVersion::new(8, 10)
+        );
+        assert_eq!(
+            "9.0".parse::<OpensshVersion>().unwrap(),
+            OpensshVersion::new(9, 0)
+        );
+    }
+
+    #[test]
+    fn check_validate_token_case_suggestion() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u %H'
+---
+        ",
+            crate_version()
+        );
+
+        let err = FrontMatter::from_str(&template).unwrap_err();
+
+        assert!(matches!(
+            err,
+            FrontMatterError::UnknownToken(
+                Token::Unknown(ref name),
+                Token::HomeDirUser
+            ) if name == "H"
+        ));
+        assert_eq!(err.to_string(), "unknown token %H; did you mean %h?");
+        assert!(FrontMatter::from_str(&template.replace(
+            "tokens: '%u %H'",
+            "tokens: '%u %H'\n    ignore_unknown_tokens: true"
+        ))
+        .unwrap()
+        .unknown_token_warnings()
+        .iter()
+        .any(|w| w.to_string() == "unknown token %H; did you mean %h?"));
+    }
+
+    #[test]
+    fn check_parse_too_large() {
+        let template = format!(
+            "---\nsshd_command:\n    command: principals\n    tokens: '%u'\n\
+             padding: '{}'\n---\n",
+            "x".repeat(FrontMatter::DEFAULT_MAX_SIZE)
+        );
+
+        assert!(matches!(
+            FrontMatter::from_str(&template),
+            Err(FrontMatterError::TooLarge(FrontMatter::DEFAULT_MAX_SIZE))
+        ));
+
+        // Without end separator nothing past the maximum is read
+        let mut reader = BufReader::new(std::io::repeat(b'x').take(u64::MAX));
+        assert!(matches!(
+            FrontMatter::parse_with_max_size(&mut reader, 64),
+            Err(FrontMatterError::TooLarge(64))
+        ));
+        let mut reader = BufReader::new(
+            b"---\n".chain(std::io::repeat(b'x').take(u64::MAX)),
+        );
+        assert!(matches!(
+            FrontMatter::parse_with_max_size(&mut reader, 64),
+            Err(FrontMatterError::TooLarge(64))
+        ));
+
+        // A larger maximum allows it
+        let mut reader = BufReader::new(template.as_bytes());
+        assert!(FrontMatter::parse_with_max_size(
+            &mut reader,
+            2 * FrontMatter::DEFAULT_MAX_SIZE
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn check_parse_kebab_case() {
+        let kebab_case = format!(
+            r"---
+sshd-command:
+    version: {}
+    command: principals
+    tokens: '%U %u'
+    complete-user: true
+    hostname-fqdn: true
+    min-uid: 1000
+    unknown-user: empty
+search_domains: [home.arpa]
+---
+        ",
+            crate_version()
+        );
+        let snake_case = kebab_case
+            .replace("sshd-command", "sshd_command")
+            .replace("complete-user", "complete_user")
+            .replace("hostname-fqdn", "hostname_fqdn")
+            .replace("min-uid", "min_uid")
+            .replace("unknown-user", "unknown_user");
+
+        let front_matter = FrontMatter::from_str(&kebab_case).unwrap();
+
+        assert_eq!(front_matter, FrontMatter::from_str(&snake_case).unwrap());
+        assert!(front_matter.sshd_command.complete_user);
+        assert_eq!(front_matter.sshd_command.min_uid, Some(1000));
+        assert!(front_matter.extra_context().get("sshd-command").is_none());
+    }
+
     #[test]
     fn check_parse_invalid_version() {
         let template = r"---
@@ -452,8 +2105,7 @@ sshd_command:
 ---
         ";
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(template);
 
         assert!(front_matter.is_ok());
         let front_matter = front_matter.unwrap().validate();
@@ -477,8 +2129,7 @@ sshd_command:
             crate_version()
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(&template);
 
         assert!(matches!(front_matter, Err(FrontMatterError::ParseError(_))));
 
@@ -500,11 +2151,23 @@ sshd_command:
             crate_version()
         );
 
-        let mut reader = BufReader::new(template.as_bytes());
-        let front_matter = FrontMatter::parse(&mut reader);
+        let front_matter = FrontMatter::from_str(&template);
         assert!(front_matter.is_ok());
     }
 
+    #[test]
+    fn check_arg_count() {
+        let mut front_matter = FrontMatter::default();
+        assert_eq!(front_matter.arg_count(), 0);
+
+        front_matter.sshd_command.tokens = FrontMatterTokens(Box::new([
+            Token::ConnectionEndpoints,
+            Token::UserId,
+            Token::UserName,
+        ]));
+        assert_eq!(front_matter.arg_count(), 6);
+    }
+
     #[test]
     fn check_validate_default() {
         assert!(FrontMatter::default().validate().is_ok());
@@ -518,7 +2181,7 @@ sshd_command:
         front_matter.sshd_command.complete_user = true;
         assert!(matches!(
             front_matter.validate(),
-            Err(FrontMatterError::ParseError(_))
+            Err(FrontMatterError::CompleteUserMissingToken)
         ));
 
         front_matter.sshd_command.tokens =
@@ -532,7 +2195,7 @@ sshd_command:
             FrontMatterTokens(Box::new([Token::HomeDirUser]));
         assert!(matches!(
             front_matter.validate(),
-            Err(FrontMatterError::ParseError(_))
+            Err(FrontMatterError::CompleteUserMissingToken)
         ));
     }
 
@@ -566,6 +2229,12 @@ sshd_command:
         {
             // `Command::Principals` supports everything
         }
+        {
+            // `Command::Generic` skips the sshd support matrix
+            front_matter.sshd_command.command = Command::Generic;
+
+            assert!(front_matter.validate().is_ok());
+        }
     }
 
     #[test]
@@ -573,14 +2242,14 @@ sshd_command:
         let crate_version = crate_version();
         let mut front_matter = FrontMatter::default();
 
-        if let Some(required_version) = update_version(&crate_version, 0, 0, 0)
+        if let Some(required_version) = update_version(crate_version, 0, 0, 0)
         {
             front_matter.sshd_command.version =
                 VersionReq::from_str(&required_version.to_string()).unwrap();
             assert!(front_matter.validate().is_ok());
         }
 
-        if let Some(required_version) = update_version(&crate_version, 1, 0, 0)
+        if let Some(required_version) = update_version(crate_version, 1, 0, 0)
         {
             front_matter.sshd_command.version =
                 VersionReq::from_str(&required_version.to_string()).unwrap();
@@ -593,8 +2262,7 @@ sshd_command:
             }
         }
 
-        if let Some(required_version) =
-            update_version(&crate_version, -1, 0, 0)
+        if let Some(required_version) = update_version(crate_version, -1, 0, 0)
         {
             front_matter.sshd_command.version =
                 VersionReq::from_str(&required_version.to_string()).unwrap();
@@ -606,7 +2274,7 @@ sshd_command:
                 panic!();
             }
         }
-        if let Some(required_version) = update_version(&crate_version, 0, 1, 0)
+        if let Some(required_version) = update_version(crate_version, 0, 1, 0)
         {
             front_matter.sshd_command.version =
                 VersionReq::from_str(&required_version.to_string()).unwrap();
@@ -619,8 +2287,7 @@ sshd_command:
             }
         }
 
-        if let Some(required_version) =
-            update_version(&crate_version, 0, -1, 0)
+        if let Some(required_version) = update_version(crate_version, 0, -1, 0)
         {
             if required_version.major != 0 {
                 front_matter.sshd_command.version =
@@ -635,7 +2302,7 @@ sshd_command:
             }
         }
 
-        if let Some(required_version) = update_version(&crate_version, 0, 0, 1)
+        if let Some(required_version) = update_version(crate_version, 0, 0, 1)
         {
             front_matter.sshd_command.version =
                 VersionReq::from_str(&required_version.to_string()).unwrap();
@@ -648,8 +2315,7 @@ sshd_command:
             }
         }
 
-        if let Some(required_version) =
-            update_version(&crate_version, 0, 0, -1)
+        if let Some(required_version) = update_version(crate_version, 0, 0, -1)
         {
             front_matter.sshd_command.version =
                 VersionReq::from_str(&required_version.to_string()).unwrap();
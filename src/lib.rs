@@ -1,115 +1,310 @@
 use std::{
     fmt::Display,
-    io::{BufReader, Read, Write},
+    io::{self, BufReader, Read, Write},
+    sync::OnceLock,
 };
 
 use semver::Version;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tera::Tera;
 
-use context::{build_context, User};
+use context::build_context;
+pub use context::{build_context_with, UserBackend, UzersBackend};
 use error::SshdCommandError;
-use frontmatter::FrontMatter;
-use macros::define_tokens;
+use frontmatter::{FrontMatter, OpensshVersion, Output};
+use macros::{debug, define_tokens};
 
+pub mod builder;
 mod context;
-mod error;
+mod delimiters;
+pub mod error;
+mod filters;
 pub mod frontmatter;
+pub mod lint;
 mod macros;
 
 define_tokens! {
     /// All possible tokens as documented in SSHD_CONFIG(5))
-    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+    #[derive(PartialEq, Eq, Hash, Clone, Debug)]
     ;
 
-    /// %C: Identifies the connection endpoints, containing four space-separated values.
+    /// Identifies the connection endpoints, containing four space-separated
+    /// values.
     ConnectionEndpoints => "%C";
 
-    /// %D: The routing domain in which the incoming connection was received.
+    /// The routing domain in which the incoming connection was received.
     RoutingDomain => "%D";
 
-    /// %F: The fingerprint of the CA key.
+    /// The fingerprint of the CA key.
     FingerPrintCaKey => "%F";
 
-    /// %f: The fingerprint of the key or certificate.
+    /// The fingerprint of the key or certificate.
     FingerPrintCaKeyOrCert => "%f";
 
-    /// %h: The home directory of the user.
+    /// The home directory of the user.
     HomeDirUser => "%h";
 
-    /// %i: The key ID in the certificate.
+    /// The key ID in the certificate.
     KeyIdCert => "%i";
 
-    /// %K: The base64-encoded CA key.
+    /// The base64-encoded CA key.
     Base64EncodedCaKey => "%K";
 
-    /// %k: The base64-encoded key or certificate for authentication.
+    /// The base64-encoded key or certificate for authentication.
     Base64EncodedAuthKeyOrCert => "%k";
 
-    /// %s: The serial number of the certificate.
+    /// The serial number of the certificate.
     CertificateSerialNumber => "%s";
 
-    /// %T: The type of the CA key.
+    /// The type of the CA key.
     CaKeyType => "%T";
 
-    /// %t: The key or certificate type.
+    /// The key or certificate type.
     CertKeyType => "%t";
 
-    /// %U: The numeric user ID of the target user.
+    /// The numeric user ID of the target user.
     UserId => "%U";
 
-    /// %u: The username.
+    /// The username.
     UserName => "%u";
+
+    /// A literal '%', consumes no argument.
+    LiteralPercent => "%%";
 }
 
 impl Token {
+    /// Number of positional arguments this token consumes.
     #[must_use]
-    pub fn get_template_args(tokens: &[Self]) -> Vec<String> {
-        // TODO: find better placeholders
-        let placeholder_tokens: Vec<String> = tokens
+    pub const fn arg_count(&self) -> usize {
+        match self {
+            Self::ConnectionEndpoints => 4,
+            Self::LiteralPercent => 0,
+            _ => 1,
+        }
+    }
+
+    /// Whether this token is known to this version.
+    #[must_use]
+    pub const fn is_known(&self) -> bool {
+        !matches!(self, Self::Unknown(_))
+    }
+
+    /// Environment variable used for the argument of this token when it's
+    /// missing and `env_fallback` is set.
+    #[must_use]
+    pub const fn env_var(&self) -> Option<&'static str> {
+        match self {
+            Self::RoutingDomain => Some("SSHD_CMD_ROUTING_DOMAIN"),
+            Self::FingerPrintCaKey => Some("SSHD_CMD_CA_FINGERPRINT"),
+            Self::FingerPrintCaKeyOrCert => Some("SSHD_CMD_KEY_FINGERPRINT"),
+            Self::HomeDirUser => Some("SSHD_CMD_HOME_DIR"),
+            Self::KeyIdCert => Some("SSHD_CMD_KEY_ID"),
+            Self::Base64EncodedCaKey => Some("SSHD_CMD_CA_KEY"),
+            Self::Base64EncodedAuthKeyOrCert => Some("SSHD_CMD_AUTH_KEY"),
+            Self::CertificateSerialNumber => Some("SSHD_CMD_SERIAL"),
+            Self::CaKeyType => Some("SSHD_CMD_CA_KEY_TYPE"),
+            Self::CertKeyType => Some("SSHD_CMD_CERT_KEY_TYPE"),
+            Self::UserId => Some("SSHD_CMD_UID"),
+            Self::UserName => Some("SSHD_CMD_USER"),
+            Self::ConnectionEndpoints
+            | Self::LiteralPercent
+            | Self::Unknown(_) => None,
+        }
+    }
+
+    /// The first OpenSSH release passing the token to the
+    /// `AuthorizedKeysCommand`/`AuthorizedPrincipalsCommand`, `None` for an
+    /// unknown token.
+    #[must_use]
+    pub const fn openssh_min(&self) -> Option<OpensshVersion> {
+        match self {
+            Self::ConnectionEndpoints | Self::RoutingDomain => {
+                Some(OpensshVersion::new(8, 3))
+            }
+            Self::FingerPrintCaKey
+            | Self::FingerPrintCaKeyOrCert
+            | Self::HomeDirUser
+            | Self::KeyIdCert
+            | Self::Base64EncodedCaKey
+            | Self::Base64EncodedAuthKeyOrCert
+            | Self::CertificateSerialNumber
+            | Self::CaKeyType
+            | Self::CertKeyType
+            | Self::UserId
+            | Self::UserName
+            | Self::LiteralPercent => Some(OpensshVersion::new(6, 9)),
+            Self::Unknown(_) => None,
+        }
+    }
+
+    /// The unknown token `s`, a `%` followed by a letter that isn't a known
+    /// token, e.g. added by a newer OpenSSH. Unlike [`Token::try_from`] this
+    /// accepts tokens unknown to this version.
+    #[must_use]
+    pub fn unknown(s: &str) -> Option<Self> {
+        let mut chars = s.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('%'), Some(c), None)
+                if c.is_ascii_alphabetic() && Self::try_from(s).is_err() =>
+            {
+                Some(Self::Unknown(c.to_string()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The known token an unknown one only differs from in case, e.g. `%h`
+    /// for `%H`.
+    #[must_use]
+    pub fn case_suggestion(&self) -> Option<Self> {
+        let Self::Unknown(name) = self else {
+            return None;
+        };
+
+        Self::ALL
             .iter()
-            .map(|token| match token {
-                Self::ConnectionEndpoints => String::from("::1 22 ::1 41644"),
-                Self::RoutingDomain => String::from("127.0.0.1/8"),
-                Self::FingerPrintCaKey => String::from("_FingerPrintCaKey_"),
-                Self::FingerPrintCaKeyOrCert => {
-                    String::from("_FingerPrintCaKeyOrCert_")
-                }
-                Self::HomeDirUser => String::from("/home/placeholder_user"),
-                Self::KeyIdCert => String::from("_KeyIdCert_"),
-                Self::Base64EncodedCaKey => {
-                    String::from("X0Jhc2U2NEVuY29kZWRDYUtleV8=")
-                }
-                Self::Base64EncodedAuthKeyOrCert => {
-                    String::from("X0Jhc2U2NEVuY29kZWRBdXRoS2V5T3JDZXJ0Xw==")
-                }
-                Self::CertificateSerialNumber => String::from("0"),
-                Self::CaKeyType => String::from("sha2-nistp384"),
-                Self::CertKeyType => {
-                    String::from("ssh-ed25519-cert-v01@openssh.com")
-                }
-                Self::UserId => User::get_current_uid().to_string(),
-                Self::UserName => User::get_current_name(),
+            .find(|token| {
+                token.to_string().eq_ignore_ascii_case(&format!("%{name}"))
             })
-            .collect();
+            .cloned()
+    }
 
-        placeholder_tokens
+    /// Context keys `build_context` may insert for this token.
+    #[must_use]
+    pub const fn context_keys(&self) -> &'static [&'static str] {
+        match self {
+            Self::ConnectionEndpoints => &[
+                "client",
+                "client_addr",
+                "client_addr_v4",
+                "client_hostname",
+                "client_port",
+                "client_scope",
+                "server",
+                "server_addr",
+                "server_addr_v4",
+                "server_port",
+                "server_scope",
+            ],
+            Self::RoutingDomain => &["routing_domain"],
+            Self::FingerPrintCaKey => &["ca_fingerprint"],
+            Self::FingerPrintCaKeyOrCert => &["key_fingerprint"],
+            Self::HomeDirUser => &["home_dir"],
+            Self::KeyIdCert => &["key_id", "key_id_num"],
+            Self::Base64EncodedCaKey => &["ca_key_b64", "ca_key_len"],
+            Self::Base64EncodedAuthKeyOrCert => {
+                &["auth_key_b64", "auth_key_len"]
+            }
+            // Unknown tokens are inserted by their name, which isn't known
+            // statically
+            Self::CertificateSerialNumber
+            | Self::LiteralPercent
+            | Self::Unknown(_) => &[],
+            Self::CaKeyType => &["ca_key_type"],
+            Self::CertKeyType => &["cert_key_type"],
+            Self::UserId | Self::UserName => &["user"],
+        }
+    }
+
+    /// Positional argument layout for `tokens`: each token paired with the
+    /// number of arguments it consumes, in the order `build_context` reads
+    /// them. E.g. `%C %u` takes four arguments for `%C` followed by one for
+    /// `%u`.
+    #[must_use]
+    pub fn from_sshd_config_order(tokens: &[Self]) -> Vec<(Self, usize)> {
+        tokens
+            .iter()
+            .map(|token| (token.clone(), token.arg_count()))
+            .collect()
+    }
+
+    #[must_use]
+    pub fn get_template_args(tokens: &[Self]) -> Vec<String> {
+        tokens.iter().filter_map(Self::placeholder).collect()
+    }
+
+    /// A realistic example argument for documentation, unlike the
+    /// placeholder of `--check`. Empty for tokens that consume no argument.
+    #[must_use]
+    pub const fn arg_example(&self) -> &'static str {
+        match self {
+            Self::ConnectionEndpoints => "192.0.2.10 41644 192.0.2.1 22",
+            Self::RoutingDomain => "rdomain0",
+            Self::FingerPrintCaKey | Self::FingerPrintCaKeyOrCert => {
+                "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"
+            }
+            Self::HomeDirUser => "/home/user",
+            Self::KeyIdCert => "user@example.com",
+            Self::Base64EncodedCaKey | Self::Base64EncodedAuthKeyOrCert => {
+                "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f"
+            }
+            Self::CertificateSerialNumber => "42",
+            Self::CaKeyType => "ssh-ed25519",
+            Self::CertKeyType => "ssh-ed25519-cert-v01@openssh.com",
+            Self::UserId => "1000",
+            Self::UserName => "user",
+            Self::LiteralPercent | Self::Unknown(_) => "",
+        }
+    }
+
+    /// Placeholder argument used by `--check`, `None` for tokens that
+    /// consume no argument.
+    fn placeholder(&self) -> Option<String> {
+        let placeholder = match self {
+            Self::ConnectionEndpoints => String::from("::1 22 ::1 41644"),
+            Self::RoutingDomain => String::from("127.0.0.1/8"),
+            Self::FingerPrintCaKey | Self::FingerPrintCaKeyOrCert => {
+                String::from(
+                    "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+                )
+            }
+            Self::HomeDirUser => String::from("/home/placeholder_user"),
+            Self::KeyIdCert => String::from("_KeyIdCert_"),
+            Self::Base64EncodedCaKey => {
+                String::from("X0Jhc2U2NEVuY29kZWRDYUtleV8=")
+            }
+            Self::Base64EncodedAuthKeyOrCert => {
+                String::from("X0Jhc2U2NEVuY29kZWRBdXRoS2V5T3JDZXJ0Xw==")
+            }
+            // Serials are unsigned 64 bit integers, CAs commonly start at 1
+            Self::CertificateSerialNumber => String::from("1"),
+            Self::CaKeyType => String::from("ecdsa-sha2-nistp384"),
+            Self::CertKeyType => {
+                String::from("ssh-ed25519-cert-v01@openssh.com")
+            }
+            // Fixed instead of the running user so `--check` is
+            // deterministic, root exists on every system so `complete_user`
+            // can look it up
+            Self::UserId => String::from("0"),
+            Self::UserName => String::from("root"),
+            Self::Unknown(_) => String::from("_Unknown_"),
+            // Consumes no argument
+            Self::LiteralPercent => return None,
+        };
+
+        Some(placeholder)
     }
 }
 
-#[derive(Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[derive(
+    Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug, Default,
+)]
 #[serde(rename_all = "lowercase")]
 pub enum Command {
     #[default]
     Keys,
     Principals,
+    /// Not for an sshd command, e.g. another sshd related config snippet,
+    /// supports every token.
+    Generic,
 }
 
 enum KeysCommand {}
 enum PrincipalCommand {}
+enum GenericCommand {}
 
 trait CommandTrait {
-    fn is_token_supported(token: Token) -> bool
+    fn is_token_supported(token: &Token) -> bool
     where
         Self: Sized;
 
@@ -119,16 +314,41 @@ trait CommandTrait {
     {
         tokens
             .iter()
-            .find(|&&t| !Self::is_token_supported(t))
-            .map_or(Ok(()), |&t| Err(t))
+            .find(|t| !Self::is_token_supported(t))
+            .map_or(Ok(()), |t| Err(t.clone()))
     }
 }
 
 impl Command {
+    /// Lowercase name of the command, as used in the front matter.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Keys => "keys",
+            Self::Principals => "principals",
+            Self::Generic => "generic",
+        }
+    }
+
+    /// Name of the `sshd_config(5)` option, `generic` has none.
     const fn option_name(self) -> &'static str {
         match self {
             Self::Keys => "AuthorizedKeysCommand",
             Self::Principals => "AuthorizedPrincipalsCommand",
+            Self::Generic => "generic",
+        }
+    }
+}
+
+impl Command {
+    /// Whether sshd passes `token` to the command, every token for
+    /// [`Command::Generic`].
+    #[must_use]
+    pub fn supports(self, token: &Token) -> bool {
+        match self {
+            Self::Keys => KeysCommand::is_token_supported(token),
+            Self::Principals => PrincipalCommand::is_token_supported(token),
+            Self::Generic => GenericCommand::is_token_supported(token),
         }
     }
 }
@@ -140,7 +360,7 @@ impl Display for Command {
 }
 
 impl CommandTrait for KeysCommand {
-    fn is_token_supported(token: Token) -> bool {
+    fn is_token_supported(token: &Token) -> bool {
         use Token as Tk;
 
         matches!(
@@ -153,12 +373,13 @@ impl CommandTrait for KeysCommand {
                 | Tk::CertKeyType
                 | Tk::UserId
                 | Tk::UserName
+                | Tk::LiteralPercent
         )
     }
 }
 
 impl CommandTrait for PrincipalCommand {
-    fn is_token_supported(token: Token) -> bool {
+    fn is_token_supported(token: &Token) -> bool {
         use Token as Tk;
 
         matches!(
@@ -176,17 +397,81 @@ impl CommandTrait for PrincipalCommand {
                 | Tk::CertKeyType
                 | Tk::UserId
                 | Tk::UserName
+                | Tk::LiteralPercent
         )
     }
 }
 
-/// # Errors
+impl CommandTrait for GenericCommand {
+    /// Every known token, unknown ones only with `ignore_unknown_tokens`.
+    fn is_token_supported(token: &Token) -> bool {
+        token.is_known()
+    }
+}
+
+/// Write a table of the tokens known to this version with their description
+/// and whether `command` supports them, or every command without one.
 ///
-/// Will return `Err` on an invalid template.
+/// # Errors
 ///
-/// # Panics
+/// Will return `Err` when writing fails.
+pub fn list_tokens(
+    writer: &mut dyn Write,
+    command: Option<Command>,
+) -> io::Result<()> {
+    const fn yes_no(supported: bool) -> &'static str {
+        if supported {
+            "yes"
+        } else {
+            "no"
+        }
+    }
+
+    let width = Token::ALL
+        .iter()
+        .map(|token| token.description().len() + 2)
+        .max()
+        .unwrap_or_default();
+
+    match command {
+        Some(_) => writeln!(
+            writer,
+            "{:<7}{:<11}{:<width$}EXAMPLE",
+            "TOKEN", "SUPPORTED", "DESCRIPTION"
+        )?,
+        None => writeln!(
+            writer,
+            "{:<7}{:<6}{:<12}{:<width$}EXAMPLE",
+            "TOKEN", "KEYS", "PRINCIPALS", "DESCRIPTION"
+        )?,
+    }
+
+    for token in Token::ALL {
+        let token_str = token.to_string();
+        let description = token.description();
+        let example = token.arg_example();
+        let supported = command.map_or_else(
+            || {
+                format!(
+                    "{:<6}{:<12}",
+                    yes_no(Command::Keys.supports(token)),
+                    yes_no(Command::Principals.supports(token)),
+                )
+            },
+            |command| format!("{:<11}", yes_no(command.supports(token))),
+        );
+        let row =
+            format!("{token_str:<7}{supported}{description:<width$}{example}");
+        // Tokens without an example would leave trailing whitespace
+        writeln!(writer, "{}", row.trim_end())?;
+    }
+
+    Ok(())
+}
+
+/// # Errors
 ///
-/// Will panic on `OsStr::to_str()` errors.
+/// Will return `Err` on an invalid template.
 pub fn render_to<I: Iterator<Item = String>, R: Read>(
     writer: &mut dyn Write,
     args: I,
@@ -196,33 +481,779 @@ pub fn render_to<I: Iterator<Item = String>, R: Read>(
     let mut reader = BufReader::new(template);
     let front_matter = FrontMatter::parse(&mut reader)?;
 
-    front_matter.validate()?;
-
-    let context = build_context(front_matter, args)?;
-
     // Read tera template
     let mut buf = String::new();
     reader
         .read_to_string(&mut buf)
         .map_err(|e| SshdCommandError::Unknown(Box::new(e)))?;
 
+    render_parsed(
+        writer,
+        args,
+        template_name,
+        front_matter,
+        RenderOptions::default(),
+        &buf,
+    )
+}
+
+/// Like [`render_to`], but renders the document for `command` from a
+/// template with multiple front matter documents, see
+/// [`FrontMatter::parse_for_command`].
+///
+/// # Errors
+///
+/// Will return `Err` on an invalid template or when no document is for
+/// `command`.
+pub fn render_command_to<I: Iterator<Item = String>, R: Read>(
+    writer: &mut dyn Write,
+    args: I,
+    template_name: &str,
+    template: R,
+    command: Command,
+) -> Result<(), SshdCommandError> {
+    let mut reader = BufReader::new(template);
+    let (front_matter, body) =
+        FrontMatter::parse_for_command(&mut reader, command)?;
+
+    render_parsed(
+        writer,
+        args,
+        template_name,
+        front_matter,
+        RenderOptions::default(),
+        &body,
+    )
+}
+
+/// Build the context `render_to` would render `template` with, as JSON.
+///
+/// # Errors
+///
+/// Will return `Err` on invalid front matter or arguments.
+pub fn context_to_json<I: Iterator<Item = String>, R: Read>(
+    args: I,
+    template: R,
+) -> Result<serde_json::Value, SshdCommandError> {
+    let mut reader = BufReader::new(template);
+    let front_matter = FrontMatter::parse(&mut reader)?;
+
+    front_matter.validate()?;
+
+    Ok(build_context(front_matter, args)?.into_json())
+}
+
+/// Options of a render set by the caller, unlike the options of the
+/// `sshd_command` front matter set by the template.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RenderOptions {
+    /// Don't fail on a `version` that doesn't match the running version.
+    pub ignore_version: bool,
+    /// Fail building the context when arguments are left over after the
+    /// last token, e.g. for tokens that don't match sshd_config.
+    pub strict_args: bool,
+    /// Trace the consumed arguments, the context and the render to stderr.
+    pub debug: bool,
+}
+
+/// Validate `front_matter`, build the context from `args` and render `body`,
+/// for a front matter that's already parsed, e.g. from a
+/// [`builder::RenderBuilder`].
+///
+/// # Errors
+///
+/// Will return `Err` on invalid front matter, arguments or template body.
+pub fn render_parsed<I: Iterator<Item = String>>(
+    writer: &mut dyn Write,
+    args: I,
+    template_name: &str,
+    front_matter: FrontMatter,
+    options: RenderOptions,
+    body: &str,
+) -> Result<(), SshdCommandError> {
+    render_with_backend(
+        writer,
+        args,
+        template_name,
+        front_matter,
+        options,
+        body,
+        &UzersBackend,
+    )
+}
+
+/// Like [`render_parsed`], but with the user and hosts database of
+/// `backend` instead of the system one.
+///
+/// # Errors
+///
+/// Will return `Err` on invalid front matter, arguments or template body.
+pub fn render_with_backend<I: Iterator<Item = String>, B: UserBackend>(
+    writer: &mut dyn Write,
+    args: I,
+    template_name: &str,
+    front_matter: FrontMatter,
+    options: RenderOptions,
+    body: &str,
+    backend: &B,
+) -> Result<(), SshdCommandError> {
+    render_with_context(
+        writer,
+        args,
+        template_name,
+        front_matter,
+        options,
+        body,
+        backend,
+    )
+    .map(|_| ())
+}
+
+/// What [`render_with_outcome`] rendered, for consumers embedding
+/// sshd-command.
+#[derive(Debug)]
+pub struct RenderOutcome {
+    /// The rendered output, empty for a denied user.
+    pub rendered: Vec<u8>,
+    /// The command of the template.
+    pub command: Command,
+    /// The `user` of the context, `None` without `%U`/`%u` arguments.
+    pub user: Option<serde_json::Value>,
+}
+
+/// Like [`render_parsed`], but return the rendered output together with the
+/// command and user it was rendered for.
+///
+/// # Errors
+///
+/// Will return `Err` on invalid front matter, arguments or template body.
+pub fn render_with_outcome<I: Iterator<Item = String>>(
+    args: I,
+    template_name: &str,
+    front_matter: FrontMatter,
+    options: RenderOptions,
+    body: &str,
+) -> Result<RenderOutcome, SshdCommandError> {
+    let command = front_matter.sshd_command.command;
+    let mut rendered = Vec::new();
+    let context = render_with_context(
+        &mut rendered,
+        args,
+        template_name,
+        front_matter,
+        options,
+        body,
+        &UzersBackend,
+    )?;
+    let user = context
+        .get("user")
+        .filter(|user| !user["name"].is_null() || !user["uid"].is_null())
+        .cloned();
+
+    Ok(RenderOutcome {
+        rendered,
+        command,
+        user,
+    })
+}
+
+/// Render like [`render_parsed`] and return the context it was rendered
+/// with.
+fn render_with_context<I: Iterator<Item = String>, B: UserBackend>(
+    writer: &mut dyn Write,
+    args: I,
+    template_name: &str,
+    front_matter: FrontMatter,
+    options: RenderOptions,
+    body: &str,
+    backend: &B,
+) -> Result<tera::Context, SshdCommandError> {
+    front_matter.validate_with(options)?;
+
+    let command = front_matter.sshd_command.command;
+    let output = front_matter.sshd_command.output;
+    let normalize_output = front_matter.sshd_command.normalize_output;
+    let autoescape = front_matter.sshd_command.autoescape;
+    let max_output_bytes = front_matter.sshd_command.max_output_bytes;
+    let debug = options.debug;
+    let body =
+        delimiters::to_tera(body, &front_matter.sshd_command.delimiters)?;
+    let context = build_context_with(front_matter, options, args, backend)?;
+
     // Load tera template
     let mut tera = Tera::default();
-    tera.add_raw_template(template_name, &buf)?;
+    // Tera picks autoescaping by the template name, e.g. `.html`, an empty
+    // suffix matches every name
+    tera.autoescape_on(if autoescape { vec![""] } else { vec![] });
+    filters::register(&mut tera, &context);
+    tera.add_raw_template(template_name, &body)?;
+
+    // Stream the output unless it's limited, post-processed or `deny()` may
+    // discard what's rendered before it
+    if max_output_bytes.is_none()
+        && output == Output::Text
+        && !normalize_output
+        && !filters::may_deny(&body)
+    {
+        let mut counted = LimitedWriter {
+            writer,
+            limit: usize::MAX,
+            written: 0,
+        };
+        tera.render_to(template_name, &context, &mut counted)?;
+        debug!(debug, "rendered {template_name}: {} bytes", counted.written);
+
+        return Ok(context);
+    }
+
+    let limit = max_output_bytes.unwrap_or(usize::MAX);
+    let too_large = |writer: &LimitedWriter| match max_output_bytes {
+        Some(limit) if writer.exceeded() => {
+            Some(SshdCommandError::OutputTooLarge(limit))
+        }
+        _ => None,
+    };
+
+    // Render tera template, the render is aborted once the limit is exceeded
+    let mut rendered = Vec::new();
+    let mut limited = LimitedWriter {
+        writer: &mut rendered,
+        limit,
+        written: 0,
+    };
+    match tera.render_to(template_name, &context, &mut limited) {
+        // A denied user gets no keys/principals, which isn't an error
+        Err(err) if filters::is_denied(&err) => {
+            debug!(debug, "rendered {template_name}: denied");
+            return Ok(context);
+        }
+        Err(err) => {
+            return Err(too_large(&limited).unwrap_or_else(|| err.into()))
+        }
+        Ok(()) => {}
+    }
+    let rendered = String::from_utf8(rendered)
+        .map_err(|e| SshdCommandError::Unknown(Box::new(e)))?;
+
+    let emitted = match output {
+        Output::Text if normalize_output => {
+            rendered.replace("\r\n", "\n").into_bytes()
+        }
+        Output::Text => rendered.into_bytes(),
+        // Quoting makes the JSON lines longer than the rendered ones
+        Output::Jsonl => {
+            let mut jsonl = Vec::new();
+            let mut limited = LimitedWriter {
+                writer: &mut jsonl,
+                limit,
+                written: 0,
+            };
+            write_jsonl(&mut limited, command, &rendered).map_err(|e| {
+                too_large(&limited)
+                    .unwrap_or_else(|| SshdCommandError::Unknown(Box::new(e)))
+            })?;
+            jsonl
+        }
+    };
+    // Only written once complete, so nothing is written when it's too large
+    writer
+        .write_all(&emitted)
+        .map_err(|e| SshdCommandError::Unknown(Box::new(e)))?;
+    debug!(debug, "rendered {template_name}: {} bytes", emitted.len());
+
+    Ok(context)
+}
+
+/// A writer failing once more than `limit` bytes would be written, a write
+/// that doesn't fit is refused as a whole.
+struct LimitedWriter<'a> {
+    writer: &'a mut dyn Write,
+    limit: usize,
+    written: usize,
+}
+
+impl LimitedWriter<'_> {
+    /// A write was refused for exceeding the limit.
+    const fn exceeded(&self) -> bool {
+        self.written > self.limit
+    }
+}
+
+impl Write for LimitedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.saturating_add(buf.len()) > self.limit {
+            self.written = self.written.saturating_add(buf.len());
+            return Err(io::Error::other("output limit exceeded"));
+        }
 
-    // Render tera template
-    tera.render_to(template_name, &context, writer)?;
+        let written = self.writer.write(buf)?;
+        self.written += written;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write every non-empty line of `rendered` as a JSON object keyed by what
+/// `command` outputs.
+fn write_jsonl(
+    writer: &mut dyn Write,
+    command: Command,
+    rendered: &str,
+) -> std::io::Result<()> {
+    let key = match command {
+        Command::Keys => "key",
+        Command::Principals => "principal",
+        Command::Generic => "line",
+    };
+
+    for line in rendered.lines().filter(|line| !line.trim().is_empty()) {
+        writeln!(writer, "{}", serde_json::json!({ key: line }))?;
+    }
 
     Ok(())
 }
 
+/// Like [`render_to`], but returns the rendered template as a [`String`].
+///
+/// # Errors
+///
+/// Will return `Err` on an invalid template or when the rendered output is
+/// not valid UTF-8.
+pub fn render_to_string<I: Iterator<Item = String>, R: Read>(
+    args: I,
+    template_name: &str,
+    template: R,
+) -> Result<String, SshdCommandError> {
+    let mut buf = Vec::new();
+    render_to(&mut buf, args, template_name, template)?;
+
+    String::from_utf8(buf).map_err(|e| SshdCommandError::Unknown(Box::new(e)))
+}
+
 #[inline]
 #[must_use]
+/// The version of this crate, parsed on the first call only.
+///
 /// # Panics
 ///
 /// Will panic when failing to parse the current crate version into a
 /// [`Version`].
-pub fn crate_version() -> Version {
-    semver::Version::parse(env!("CARGO_PKG_VERSION"))
-        .expect("CARGO_PKG_VERSION is always valid")
+pub fn crate_version() -> &'static Version {
+    static CRATE_VERSION: OnceLock<Version> = OnceLock::new();
+
+    CRATE_VERSION.get_or_init(|| {
+        semver::Version::parse(env!("CARGO_PKG_VERSION"))
+            .expect("CARGO_PKG_VERSION is always valid")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn check_render_to_string() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u'
+---
+{{{{ user.name }}}}@host",
+            crate_version()
+        );
+
+        let rendered = render_to_string(
+            [String::from("user")].into_iter(),
+            "template",
+            template.as_bytes(),
+        );
+
+        assert_eq!(rendered.unwrap(), "user@host");
+    }
+
+    #[test]
+    fn check_render_with_backend() {
+        struct Backend;
+
+        impl UserBackend for Backend {
+            fn user_by_uid(&self, uid: u32) -> Option<uzers::User> {
+                Some(uzers::User::new(uid, "backend", uid))
+            }
+
+            fn user_by_name(&self, _name: &str) -> Option<uzers::User> {
+                None
+            }
+
+            fn user_groups(
+                &self,
+                _user: &uzers::User,
+            ) -> Option<Vec<uzers::Group>> {
+                Some(Vec::new())
+            }
+
+            fn group_by_gid(&self, _gid: u32) -> Option<uzers::Group> {
+                None
+            }
+        }
+
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%U'
+    complete_user: true
+---
+        ",
+            crate_version()
+        );
+        let front_matter = FrontMatter::from_str(&template).unwrap();
+        let mut rendered = Vec::new();
+
+        render_with_backend(
+            &mut rendered,
+            [String::from("4242")].into_iter(),
+            "template",
+            front_matter,
+            RenderOptions::default(),
+            "{{ user.name }}",
+            &Backend,
+        )
+        .unwrap();
+
+        assert_eq!(rendered, b"backend");
+    }
+
+    #[test]
+    fn check_crate_version_cached() {
+        // Every call returns the version parsed by the first one
+        assert!(std::ptr::eq(crate_version(), crate_version()));
+        assert_eq!(crate_version().to_string(), env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn check_command_supports() {
+        assert!(Command::Keys.supports(&Token::UserName));
+        assert!(Command::Keys.supports(&Token::Base64EncodedAuthKeyOrCert));
+        assert!(!Command::Keys.supports(&Token::KeyIdCert));
+        assert!(!Command::Keys.supports(&Token::CaKeyType));
+
+        assert!(Command::Principals.supports(&Token::KeyIdCert));
+        assert!(Command::Principals.supports(&Token::CaKeyType));
+        assert!(
+            !Command::Principals.supports(&Token::Unknown(String::from("X")))
+        );
+
+        assert!(Command::Generic.supports(&Token::KeyIdCert));
+        assert!(!Command::Generic.supports(&Token::Unknown(String::from("X"))));
+    }
+
+    #[test]
+    fn check_sshd_config_order() {
+        let layout = Token::from_sshd_config_order(&[
+            Token::UserName,
+            Token::ConnectionEndpoints,
+            Token::UserId,
+        ]);
+
+        assert_eq!(
+            layout,
+            [
+                (Token::UserName, 1),
+                (Token::ConnectionEndpoints, 4),
+                (Token::UserId, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_literal_percent() {
+        let tokens = [Token::UserName, Token::LiteralPercent, Token::UserId];
+
+        assert_eq!(Token::try_from("%%"), Ok(Token::LiteralPercent));
+        assert_eq!(Token::LiteralPercent.to_string(), "%%");
+        assert_eq!(Token::get_template_args(&tokens).len(), 2);
+
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: keys
+    tokens: '%u %% %U'
+---
+{{{{ user.name }}}} {{{{ user.uid }}}}",
+            crate_version()
+        );
+        let args = ["user", "1000"].map(String::from);
+
+        assert_eq!(
+            render_to_string(args.into_iter(), "test", template.as_bytes())
+                .unwrap(),
+            "user 1000"
+        );
+    }
+
+    #[test]
+    fn check_template_args() {
+        let tokens = [
+            Token::UserId,
+            Token::UserName,
+            Token::LiteralPercent,
+            Token::HomeDirUser,
+        ];
+
+        assert_eq!(
+            Token::get_template_args(&tokens),
+            ["0", "root", "/home/placeholder_user"]
+        );
+    }
+
+    #[test]
+    fn check_command_as_str() {
+        for command in [Command::Keys, Command::Principals, Command::Generic] {
+            let deserialized: Command =
+                serde_yaml::from_str(command.as_str()).unwrap();
+
+            assert_eq!(deserialized, command);
+        }
+        assert_eq!(Command::Principals.as_str(), "principals");
+    }
+
+    #[test]
+    fn check_render_parsed() {
+        let front_matter = builder::RenderBuilder::new(Command::Keys)
+            .tokens([Token::UserName])
+            .build();
+        let mut buf = Vec::new();
+        render_parsed(
+            &mut buf,
+            [String::from("user")].into_iter(),
+            "test",
+            front_matter,
+            RenderOptions::default(),
+            "{{ user.name }}",
+        )
+        .unwrap();
+
+        assert_eq!(buf, b"user");
+    }
+
+    #[test]
+    fn check_render_user_without_complete() {
+        let rendered = builder::RenderBuilder::new(Command::Principals)
+            .tokens([Token::UserId])
+            .render(
+                std::iter::once(String::from("1000")),
+                "{{ user.uid }}:{{ user.name | default(value='unknown') }}",
+            );
+
+        assert_eq!(rendered.unwrap(), "1000:unknown");
+    }
+
+    #[test]
+    fn check_render_normalize_output() {
+        let render = |normalize_output| {
+            builder::RenderBuilder::new(Command::Keys)
+                .tokens([Token::UserName])
+                .normalize_output(normalize_output)
+                .render(
+                    std::iter::once(String::from("user")),
+                    "{{ user.name }}\r\nstatic\r\n",
+                )
+                .unwrap()
+        };
+
+        assert_eq!(render(false), "user\r\nstatic\r\n");
+        assert_eq!(render(true), "user\nstatic\n");
+    }
+
+    #[test]
+    fn check_render_autoescape() {
+        let render = |autoescape| {
+            builder::RenderBuilder::new(Command::Principals)
+                .tokens([Token::KeyIdCert])
+                .autoescape(autoescape)
+                .render(std::iter::once(String::from("a&b")), "{{ key_id }}")
+                .unwrap()
+        };
+
+        assert_eq!(render(false), "a&b");
+        assert_eq!(render(true), "a&amp;b");
+    }
+
+    #[test]
+    fn check_render_deny() {
+        let render = |user: &str, body| {
+            builder::RenderBuilder::new(Command::Principals)
+                .tokens([Token::UserName])
+                .render(std::iter::once(String::from(user)), body)
+        };
+        let body = "before\n{% if user.name != 'admin' %}{{ deny() }}\
+                    {% endif %}{{ user.name }}";
+
+        assert_eq!(render("admin", body).unwrap(), "before\nadmin");
+        // Nothing is written, not even what was rendered before `deny()`
+        assert_eq!(render("user", body).unwrap(), "");
+
+        // Other errors of a template using `deny()` aren't swallowed
+        assert!(matches!(
+            render(
+                "user",
+                "{% if user.name == 'admin' %}{{ deny() }}{% endif %}\
+                 {{ missing }}"
+            ),
+            Err(SshdCommandError::Tera(_))
+        ));
+    }
+
+    #[test]
+    fn check_render_with_outcome() {
+        let front_matter = builder::RenderBuilder::new(Command::Principals)
+            .tokens([Token::UserId, Token::UserName])
+            .build();
+        let outcome = render_with_outcome(
+            [String::from("1000"), String::from("user")].into_iter(),
+            "test",
+            front_matter,
+            RenderOptions::default(),
+            "{{ user.name }}",
+        )
+        .unwrap();
+
+        assert_eq!(outcome.rendered, b"user");
+        assert_eq!(outcome.command, Command::Principals);
+        let user = outcome.user.unwrap();
+        assert_eq!(user["name"], "user");
+        assert_eq!(user["uid"], 1000);
+
+        let front_matter = builder::RenderBuilder::new(Command::Keys)
+            .tokens([Token::HomeDirUser])
+            .build();
+        let outcome = render_with_outcome(
+            [String::from("/home/user")].into_iter(),
+            "test",
+            front_matter,
+            RenderOptions::default(),
+            "{{ home_dir }}",
+        )
+        .unwrap();
+
+        assert_eq!(outcome.rendered, b"/home/user");
+        assert_eq!(outcome.command, Command::Keys);
+        assert!(outcome.user.is_none());
+    }
+
+    #[test]
+    fn check_render_max_output_bytes() {
+        let render = |max_output_bytes| {
+            builder::RenderBuilder::new(Command::Principals)
+                .tokens([Token::UserName])
+                .max_output_bytes(max_output_bytes)
+                .render(
+                    std::iter::once(String::from("user")),
+                    "{% for i in range(end=100) %}{{ user.name }}\n{% endfor %}",
+                )
+        };
+
+        assert_eq!(render(500).unwrap().len(), 500);
+        assert!(matches!(
+            render(499),
+            Err(SshdCommandError::OutputTooLarge(499))
+        ));
+    }
+
+    #[test]
+    fn check_render_max_output_bytes_aborts() {
+        let mut buf = Vec::new();
+        let front_matter = builder::RenderBuilder::new(Command::Principals)
+            .tokens([Token::UserName])
+            .output(Output::Jsonl)
+            .max_output_bytes(500)
+            .build();
+
+        // The JSON lines exceed the limit, the rendered lines don't
+        let result = render_parsed(
+            &mut buf,
+            std::iter::once(String::from("user")),
+            "test",
+            front_matter.clone(),
+            RenderOptions::default(),
+            "{% for i in range(end=50) %}{{ user.name }}\n{% endfor %}",
+        );
+        assert!(matches!(result, Err(SshdCommandError::OutputTooLarge(500))));
+        assert!(buf.is_empty());
+
+        // Rendering stops at the limit instead of running to completion
+        let result = render_parsed(
+            &mut buf,
+            std::iter::once(String::from("user")),
+            "test",
+            front_matter,
+            RenderOptions::default(),
+            "{% for i in range(end=100000) %}{% for j in range(end=100000) %}\
+             {{ user.name }}\n{% endfor %}{% endfor %}",
+        );
+        assert!(matches!(result, Err(SshdCommandError::OutputTooLarge(500))));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn check_list_tokens() {
+        let mut buf = Vec::new();
+        list_tokens(&mut buf, Some(Command::Keys)).unwrap();
+        let table = String::from_utf8(buf).unwrap();
+
+        assert_eq!(table.lines().count(), Token::ALL.len() + 1);
+        assert!(table.starts_with("TOKEN  SUPPORTED  DESCRIPTION "));
+        assert!(table.lines().next().unwrap().ends_with(" EXAMPLE"));
+        assert!(table.contains("%u     yes        The username. "));
+        assert!(table.contains("  user\n"));
+        assert!(table.contains(
+            "%%     yes        A literal '%', consumes no argument.\n"
+        ));
+
+        let mut buf = Vec::new();
+        list_tokens(&mut buf, None).unwrap();
+        let table = String::from_utf8(buf).unwrap();
+
+        assert!(table.starts_with("TOKEN  KEYS  PRINCIPALS  DESCRIPTION "));
+        assert!(table.contains("%K     no    yes         "));
+        assert!(table.contains("  192.0.2.10 41644 192.0.2.1 22\n"));
+    }
+
+    #[test]
+    fn check_description() {
+        assert_eq!(
+            Token::ConnectionEndpoints.description(),
+            "Identifies the connection endpoints, containing four \
+             space-separated values."
+        );
+        assert_eq!(Token::UserName.description(), "The username.");
+        assert_eq!(
+            Token::Unknown(String::from("Z")).description(),
+            "A token unknown to this version."
+        );
+        assert!(!Token::ALL.contains(&Token::Unknown(String::from("Z"))));
+        assert!(Token::ALL.iter().all(Token::is_known));
+    }
+
+    #[test]
+    fn check_unknown_not_parsed() {
+        assert_eq!(Token::try_from("%Z"), Err("%Z"));
+        assert_eq!(
+            Token::unknown("%Z"),
+            Some(Token::Unknown(String::from("Z")))
+        );
+        assert_eq!(Token::unknown("%u"), None);
+        assert_eq!(Token::unknown("%ZZ"), None);
+        assert_eq!(Token::unknown("%1"), None);
+    }
 }
@@ -0,0 +1,328 @@
+use std::collections::BTreeSet;
+
+use tera::{
+    ast::{Expr, ExprVal, FunctionCall, Node},
+    Tera,
+};
+
+use crate::{
+    context::build_context, delimiters, error::SshdCommandError,
+    frontmatter::FrontMatter, Token,
+};
+
+/// Parse the template `body`, written with the delimiters of
+/// `front_matter`, and collect the variables it doesn't define itself.
+fn free_variables(
+    front_matter: &FrontMatter,
+    body: &str,
+) -> Result<FreeVariables, SshdCommandError> {
+    let body =
+        delimiters::to_tera(body, &front_matter.sshd_command.delimiters)?;
+
+    let mut tera = Tera::default();
+    tera.add_raw_template("body", &body)?;
+
+    let mut walker = FreeVariables::default();
+    walker.nodes(&tera.get_template("body")?.ast);
+
+    Ok(walker)
+}
+
+/// Tokens from the front matter whose context keys are never referenced in
+/// the template `body`.
+///
+/// Tokens that don't insert anything into the context are never reported.
+///
+/// # Errors
+///
+/// Will return `Err` on an invalid template body.
+pub fn unused_tokens(
+    front_matter: &FrontMatter,
+    body: &str,
+) -> Result<Vec<Token>, SshdCommandError> {
+    let variables = free_variables(front_matter, body)?;
+
+    Ok(front_matter
+        .tokens()
+        .iter()
+        .filter(|token| {
+            let keys = token.context_keys();
+            !keys.is_empty() && !keys.iter().any(|k| variables.references(k))
+        })
+        .cloned()
+        .collect())
+}
+
+/// Variables the template `body` references that are neither in the context
+/// built from `front_matter` and `args` nor defined by the template itself,
+/// e.g. with `{% set %}` or a for loop.
+///
+/// Unlike rendering this also finds references behind conditionals.
+/// References tested with `is defined` or passed through the `default` filter
+/// are never reported.
+///
+/// # Errors
+///
+/// Will return `Err` on invalid front matter, arguments or template body.
+pub fn undefined_variables<I: Iterator<Item = String>>(
+    front_matter: FrontMatter,
+    args: I,
+    body: &str,
+) -> Result<BTreeSet<String>, SshdCommandError> {
+    front_matter.validate()?;
+
+    let variables = free_variables(&front_matter, body)?;
+    let context = build_context(front_matter, args)?;
+
+    Ok(variables
+        .unguarded
+        .into_iter()
+        .filter(|variable| !context.contains_key(variable))
+        .collect())
+}
+
+/// Walks a Tera AST collecting the variables that aren't defined by the
+/// template itself.
+#[derive(Default)]
+struct FreeVariables {
+    /// Variables defined by the template in the current scope.
+    locals: Vec<String>,
+    /// Variables referenced without `is defined` or `default` guarding them.
+    unguarded: BTreeSet<String>,
+    /// Variables only referenced with a guard.
+    guarded: BTreeSet<String>,
+}
+
+impl FreeVariables {
+    fn references(&self, variable: &str) -> bool {
+        self.unguarded.contains(variable) || self.guarded.contains(variable)
+    }
+
+    fn nodes(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::VariableBlock(_, expr) => self.expr(expr),
+            Node::MacroDefinition(_, definition, _) => {
+                definition
+                    .args
+                    .values()
+                    .flatten()
+                    .for_each(|e| self.expr(e));
+
+                let scope = self.locals.len();
+                self.locals.extend(definition.args.keys().cloned());
+                self.nodes(&definition.body);
+                self.locals.truncate(scope);
+            }
+            Node::Set(_, set) => {
+                self.expr(&set.value);
+                self.locals.push(set.key.clone());
+            }
+            Node::FilterSection(_, section, _) => {
+                self.call(&section.filter);
+                self.nodes(&section.body);
+            }
+            Node::Block(_, block, _) => self.nodes(&block.body),
+            Node::Forloop(_, forloop, _) => {
+                self.expr(&forloop.container);
+
+                let scope = self.locals.len();
+                self.locals.extend(forloop.key.iter().cloned());
+                self.locals.push(forloop.value.clone());
+                self.locals.push(String::from("loop"));
+                self.nodes(&forloop.body);
+                self.locals.truncate(scope);
+
+                if let Some(body) = &forloop.empty_body {
+                    self.nodes(body);
+                }
+            }
+            Node::If(condition, _) => {
+                for (_, expr, body) in &condition.conditions {
+                    self.expr(expr);
+                    self.nodes(body);
+                }
+                if let Some((_, body)) = &condition.otherwise {
+                    self.nodes(body);
+                }
+            }
+            Node::Super
+            | Node::Text(_)
+            | Node::Extends(..)
+            | Node::Include(..)
+            | Node::ImportMacro(..)
+            | Node::Raw(..)
+            | Node::Break(_)
+            | Node::Continue(_)
+            | Node::Comment(..) => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        // `default` accepts an undefined variable
+        let defaulted =
+            expr.filters.first().is_some_and(|f| f.name == "default");
+        match &expr.val {
+            ExprVal::Ident(ident) if defaulted => self.ident(ident, true),
+            val => self.val(val),
+        }
+
+        for filter in &expr.filters {
+            self.call(filter);
+        }
+    }
+
+    fn val(&mut self, val: &ExprVal) {
+        match val {
+            ExprVal::Ident(ident) => self.ident(ident, false),
+            ExprVal::Math(math) => {
+                self.expr(&math.lhs);
+                self.expr(&math.rhs);
+            }
+            ExprVal::Logic(logic) => {
+                self.expr(&logic.lhs);
+                self.expr(&logic.rhs);
+            }
+            ExprVal::In(in_expr) => {
+                self.expr(&in_expr.lhs);
+                self.expr(&in_expr.rhs);
+            }
+            ExprVal::Test(test) => {
+                let guarded =
+                    matches!(test.name.as_str(), "defined" | "undefined");
+                self.ident(&test.ident, guarded);
+                test.args.iter().for_each(|e| self.expr(e));
+            }
+            ExprVal::MacroCall(call) => {
+                call.args.values().for_each(|e| self.expr(e));
+            }
+            ExprVal::FunctionCall(call) => self.call(call),
+            ExprVal::Array(exprs) => exprs.iter().for_each(|e| self.expr(e)),
+            ExprVal::StringConcat(concat) => {
+                concat.values.iter().for_each(|v| self.val(v));
+            }
+            ExprVal::String(_)
+            | ExprVal::Int(_)
+            | ExprVal::Float(_)
+            | ExprVal::Bool(_) => {}
+        }
+    }
+
+    fn call(&mut self, call: &FunctionCall) {
+        call.args.values().for_each(|e| self.expr(e));
+    }
+
+    /// Record the variable of `ident`, e.g. `user` of `user.groups[0]`.
+    fn ident(&mut self, ident: &str, guarded: bool) {
+        let variable = ident.split(['.', '[']).next().unwrap_or(ident);
+
+        if variable == "__tera_context"
+            || self.locals.iter().any(|local| local == variable)
+        {
+            return;
+        }
+
+        if guarded {
+            self.guarded.insert(variable.to_owned());
+        } else {
+            self.unguarded.insert(variable.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::crate_version;
+
+    #[test]
+    fn check_unused_tokens() {
+        let template = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%C %u %i'
+---
+{{{{ user.name }}}}
+{{% if key_id == 'admin' %}}client{{% endif %}}
+        ",
+            crate_version()
+        );
+
+        let mut reader = BufReader::new(template.as_bytes());
+        let front_matter = FrontMatter::parse(&mut reader).unwrap();
+
+        let mut body = String::new();
+        std::io::Read::read_to_string(&mut reader, &mut body).unwrap();
+
+        assert_eq!(
+            unused_tokens(&front_matter, &body).unwrap(),
+            [Token::ConnectionEndpoints]
+        );
+    }
+
+    #[test]
+    fn check_unused_tokens_delimiters() {
+        let front_matter: FrontMatter = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u %i'
+    delimiters:
+        variable: ['[[', ']]']
+---
+",
+            crate_version()
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            unused_tokens(&front_matter, "[[ user.name ]] {{ key_id }}")
+                .unwrap(),
+            [Token::KeyIdCert]
+        );
+    }
+
+    #[test]
+    fn check_undefined_variables() {
+        let front_matter: FrontMatter = format!(
+            r"---
+sshd_command:
+    version: {}
+    command: principals
+    tokens: '%u'
+domain: home.arpa
+---
+",
+            crate_version()
+        )
+        .parse()
+        .unwrap();
+
+        let undefined = undefined_variables(
+            front_matter,
+            [String::from("user")].into_iter(),
+            r"{{ user.name }}@{{ domain }}
+{% if user.name == 'admin' %}{{ admin_suffix }}{{ admin_suffix }}{% endif %}
+{% for group in groups %}{{ group ~ loop.index }}{% endfor %}
+{% set local = 1 %}{{ local }}{{ maybe | default(value='') }}
+{% if other is defined %}{{ key_id }}{% endif %}",
+        )
+        .unwrap();
+
+        assert_eq!(
+            undefined.into_iter().collect::<Vec<_>>(),
+            ["admin_suffix", "groups", "key_id"]
+        );
+    }
+}
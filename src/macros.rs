@@ -11,6 +11,18 @@
 ///   `<$ty>::from_str`.
 ///   On failure return `Err(SshdCommandError::InvalidArgumentToken($token))`.
 ///
+/// - (2) `arg!($args, $token; default $default)`
+///   Like (0), but falls back to `$default` (an `Option<String>`) when no
+///   argument exists.
+///
+/// - (3) `arg!($args, $ty, $token, $index, $description)`
+///   Like (1), for tokens with multiple arguments. If no argument exists
+///   returns a `Err(SshdCommandError::MissingTokenArgumentAt($token, $index,
+///   $description))`.
+///
+/// - (4) `arg!($args, $ty, $token; default $default)`
+///   Like (2), and parses the argument into type `$ty` like (1).
+///
 /// # Examples
 ///
 /// ```ignore
@@ -22,6 +34,14 @@
 /// // Get an argument and parse it into a `u16`
 /// let port  = next_arg!(token, u16, Token::ConnectionEndPoints);
 /// let port: u16 = next_arg!(token, _, Token::ConnectionEndPoints);
+///
+/// // Get the first of multiple arguments of a token
+/// let addr: IpAddr =
+///     next_arg!(token, _, Token::ConnectionEndPoints, 1, "client address");
+///
+/// // Get an argument or fall back to a default
+/// let username =
+///     next_arg!(token, Token::UserName; default Some("nobody".into()));
 /// ```
 macro_rules! next_arg {
     // (0)
@@ -43,6 +63,54 @@ macro_rules! next_arg {
             })?
         }
     }};
+
+    // (2)
+    ($args:expr, $token:expr; default $default:expr) => {{
+        match $args.next() {
+            Some(arg) => arg,
+            None => ($default).ok_or(
+                crate::error::SshdCommandError::MissingTokenArgument($token),
+            )?,
+        }
+    }};
+
+    // (3)
+    (
+        $args:expr,
+        $ty:ty,
+        $token:expr,
+        $index:literal,
+        $description:literal
+    ) => {{
+        {
+            let arg = $args.next().ok_or(
+                crate::error::SshdCommandError::MissingTokenArgumentAt(
+                    $token,
+                    $index,
+                    $description,
+                ),
+            )?;
+            <$ty as std::str::FromStr>::from_str(&arg).map_err(|_| {
+                crate::error::SshdCommandError::InvalidTokenArgument(
+                    $token,
+                    arg.clone(),
+                )
+            })?
+        }
+    }};
+
+    // (4)
+    ($args:expr, $ty:ty, $token:expr; default $default:expr) => {{
+        {
+            let arg = next_arg!($args, $token; default $default);
+            <$ty as std::str::FromStr>::from_str(&arg).map_err(|_| {
+                crate::error::SshdCommandError::InvalidTokenArgument(
+                    $token,
+                    arg.clone(),
+                )
+            })?
+        }
+    }};
 }
 
 macro_rules! define_tokens {
@@ -52,32 +120,53 @@ macro_rules! define_tokens {
         // doc comment
         ;
 
+        // The doc comment of a token is also its description
         $(
-            $(#[$meta:meta])*
-            $variant:ident => $variant_str:expr;
+            $(#[doc = $doc:literal])+
+            $variant:ident => $variant_str:literal;
         )+
     ) => {
         $(#[$enum_attr])*
         pub enum Token {
             $(
-                $(#[$meta])*
+                #[doc = concat!($variant_str, ":")]
+                $(#[doc = $doc])+
                 $variant,
             )+
+
+            /// A `%`-token unknown to this version, e.g. added by a newer
+            /// OpenSSH, by its name without the `%`. Only accepted with
+            /// `ignore_unknown_tokens`.
+            Unknown(String),
         }
 
         impl Token {
-            const fn as_str(&self) -> &'static str {
+            /// All tokens known to this version.
+            pub const ALL: &'static [Self] = &[$(Self::$variant),+];
+
+            /// Human-readable description of the token.
+            #[must_use]
+            pub const fn description(&self) -> &'static str {
                 match self {
                     $(
-                        Self::$variant => $variant_str,
+                        // Doc comment lines start with a space
+                        Self::$variant => {
+                            concat!($($doc),+).trim_ascii_start()
+                        }
                     )+
+                    Self::Unknown(_) => "A token unknown to this version.",
                 }
             }
         }
 
         impl std::fmt::Display for Token {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                f.write_str(self.as_str())
+                match self {
+                    $(
+                        Self::$variant => f.write_str($variant_str),
+                    )+
+                    Self::Unknown(name) => write!(f, "%{name}"),
+                }
             }
         }
 
@@ -97,5 +186,16 @@ macro_rules! define_tokens {
     }
 }
 
+/// Print a `debug:` trace line to stderr when `$enabled`, e.g. for the
+/// `--debug` flag. The arguments are only formatted when enabled.
+macro_rules! debug {
+    ($enabled:expr, $($arg:tt)*) => {
+        if $enabled {
+            eprintln!("debug: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use debug;
 pub(crate) use define_tokens;
 pub(crate) use next_arg;
@@ -1,109 +1,1087 @@
 use std::{
+    collections::hash_map::RandomState,
     env,
     error::Error,
-    fs::File,
-    io::{self, BufReader, Seek, Write},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{self, BufReader, Cursor, IsTerminal, Read, Seek, Write},
+    iter::Peekable,
+    path::{Path, PathBuf},
     process::ExitCode,
+    sync::mpsc,
+    thread,
+    time::Duration,
 };
 
 use sshd_command::{
-    crate_version, frontmatter::FrontMatter, render_to, Token,
+    context_to_json, crate_version,
+    error::SshdCommandError,
+    frontmatter::{FrontMatter, FrontMatterError},
+    lint::{undefined_variables, unused_tokens},
+    list_tokens, render_parsed, Command, RenderOptions, Token,
 };
 
-fn print_error_chain(mut err: &dyn Error) {
-    eprintln!("Error: {err}");
+trait ReadSeek: Read + Seek {}
 
-    while let Some(source) = err.source() {
-        eprintln!("Caused by: {source}");
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// A template path with a reader of the template.
+type Template = (String, BufReader<Box<dyn ReadSeek>>);
+
+/// A writer discarding its input while counting the non-empty lines.
+#[derive(Default)]
+struct LineCounter {
+    lines: usize,
+    /// The current line has non-whitespace content.
+    in_line: bool,
+}
+
+impl LineCounter {
+    /// The number of non-empty lines, including an unterminated last line.
+    fn lines(&self) -> usize {
+        self.lines + usize::from(self.in_line)
+    }
+}
+
+impl Write for LineCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            if *byte == b'\n' {
+                self.lines += usize::from(self.in_line);
+                self.in_line = false;
+            } else if !byte.is_ascii_whitespace() {
+                self.in_line = true;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How errors are printed to stderr.
+#[derive(Default, Clone, Copy)]
+enum ErrorFormat {
+    #[default]
+    Text,
+    /// A JSON array with a `{"message": ...}` object per error in the chain.
+    Json,
+}
+
+impl ErrorFormat {
+    fn parse(format: &str) -> Option<Self> {
+        match format {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// When to color the error output.
+#[derive(Default, Clone, Copy)]
+enum Color {
+    /// When stderr is a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    fn parse(color: &str) -> Option<Self> {
+        match color {
+            "auto" => Some(Self::Auto),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+
+    fn enabled(self) -> bool {
+        match self {
+            Self::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && io::stderr().is_terminal()
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Print `err` and its sources, only `err` itself with `--quiet`.
+fn print_error_chain(mut err: &dyn Error, flags: &Flags) {
+    let mut chain = vec![err.to_string()];
+    while let Some(source) = err.source().filter(|_| !flags.quiet) {
+        chain.push(source.to_string());
         err = source;
     }
+
+    match flags.error_format {
+        ErrorFormat::Text => {
+            // Bold red and dim
+            let (error, caused_by) = if flags.color.enabled() {
+                ("\x1b[1;31mError:\x1b[0m", "\x1b[2mCaused by:\x1b[0m")
+            } else {
+                ("Error:", "Caused by:")
+            };
+
+            let mut chain = chain.iter();
+            if let Some(message) = chain.next() {
+                eprintln!("{error} {message}");
+            }
+            for message in chain {
+                eprintln!("{caused_by} {message}");
+            }
+        }
+        ErrorFormat::Json => {
+            let chain: Vec<_> = chain
+                .iter()
+                .map(|message| serde_json::json!({ "message": message }))
+                .collect();
+            eprintln!("{}", serde_json::Value::Array(chain));
+        }
+    }
 }
 
-fn main() -> Result<ExitCode, Box<dyn Error>> {
-    let mut args = env::args().skip(1).peekable();
-    let mut check_arg = false;
-    let mut validate_arg = false;
+/// Exit code for invalid front matter, e.g. an unsupported token.
+const EXIT_FRONT_MATTER: u8 = 2;
+/// Exit code for failing to render the Tera template.
+const EXIT_TERA: u8 = 3;
 
-    'flags: while let Some(arg) = args.next_if(|a| a.starts_with('-')).as_ref()
-    {
-        match arg.as_str() {
-            "-h" | "--help" => {
-                print!(
-                    "\
+/// The exit code for `err`, so scripts can tell failures apart.
+fn error_exit_code(err: &SshdCommandError) -> ExitCode {
+    match err {
+        SshdCommandError::FrontMatter(_) => ExitCode::from(EXIT_FRONT_MATTER),
+        SshdCommandError::Tera(_) => ExitCode::from(EXIT_TERA),
+        _ => ExitCode::FAILURE,
+    }
+}
+
+fn print_help() {
+    print!(
+        "\
 {} {}
 {}
 
 USAGE:
-    sshd-command [FLAGS] [template]
+    sshd-command [FLAGS] [template] [args]...
+    sshd-command [FLAGS] <template> <template>... -- [args]...
+    sshd-command [FLAGS] --stdin [args]...
+    sshd-command list-tokens [keys|principals|generic]
+    sshd-command init --command <command> [--tokens <tokens>]
 
 ARGS:
-    <template>    Sets the template file to use
+    <template>    Sets the template file(s) to use, multiple templates are
+                  rendered in order with the same arguments
+    <args>        Arguments for the tokens in the template front matter
 
 FLAGS:
     -h, --help                     Prints help information
     -v, --validate <template>      Validate the template front matter
+        --validate-all <dir>       Validate the front matter of every .tera
+                                   file in <dir> and its subdirectories
     -c, --check <template>         Check the template (superset of validate)
+        --count                    With --check, print the number of
+                                   non-empty lines rendered to stderr
+        --stdin                    Read the template from standard input
+        --print-sshd-config <template>
+                                   Print the sshd_config line for the template
+        --lint <template>          Warn about tokens the template never uses
+        --lint-strict <template>   Like --lint, but fail on warnings
+        --dump-context <template>  Print the template context as JSON
+        --format <template>        Print the template with its front matter
+                                   normalized, use --output to replace it
+        --command <command>        Use the front matter document for
+                                   keys/principals from a template with
+                                   multiple documents
+        --output <path>            Write the rendered output to <path>, the
+                                   file is only replaced on success
+        --name <name>              Name the template <name> in Tera errors
+                                   instead of its file name
+        --args-file <path>         Append the lines of <path> to the
+                                   arguments, `-` reads standard input
+        --ignore-version           Warn instead of failing when the template
+                                   requires another sshd-command version
+        --strict-args              Fail when arguments are left over after
+                                   the last token
+    -d, --debug                    Trace parsing and rendering to stderr,
+                                   long arguments are truncated
+        --error-format <format>    Print errors as text (default) or json
+        --color <when>             Color errors: auto (default), always or
+                                   never, auto colors when stderr is a
+                                   terminal and NO_COLOR isn't set
+        --no-color                 Same as --color never
+        --timeout <seconds>        Abort when running takes longer than
+                                   <seconds>, output of earlier templates may
+                                   already be written
+    -q, --quiet                    Only print errors, not their causes
     -V, --version                  Prints version information
+
+EXIT STATUS:
+    0    Success
+    1    Other errors, e.g. failing to read a template or invalid arguments
+    2    Invalid front matter, e.g. an unsupported token
+    3    Failing to render the Tera template
 ",
-                    env!("CARGO_PKG_NAME"),
-                    crate_version(),
-                    env!("CARGO_PKG_DESCRIPTION"),
-                );
+        env!("CARGO_PKG_NAME"),
+        crate_version(),
+        env!("CARGO_PKG_DESCRIPTION"),
+    );
+
+    println!("\nTOKENS:");
+    for token in Token::ALL {
+        println!("    {:<4}{}", token.to_string(), token.description());
+    }
+}
+
+fn lint<R: Read>(
+    reader: &mut BufReader<R>,
+    strict: bool,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let front_matter = FrontMatter::parse(reader)?;
+    warn_unknown_tokens(&front_matter);
+    front_matter.validate()?;
+
+    let mut body = String::new();
+    reader.read_to_string(&mut body)?;
+
+    let unused_tokens = unused_tokens(&front_matter, &body)?;
+    for token in &unused_tokens {
+        eprintln!("Warning: token {token} is never used in the template");
+    }
+
+    if strict && !unused_tokens.is_empty() {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[derive(Default, Clone)]
+#[expect(clippy::struct_excessive_bools)]
+struct Flags {
+    check: bool,
+    count: bool,
+    validate: bool,
+    validate_all: Option<PathBuf>,
+    stdin: bool,
+    print_sshd_config: bool,
+    lint: bool,
+    lint_strict: bool,
+    dump_context: bool,
+    timeout: Option<Duration>,
+    output: Option<PathBuf>,
+    args_file: Option<PathBuf>,
+    name: Option<String>,
+    command: Option<Command>,
+    error_format: ErrorFormat,
+    color: Color,
+    ignore_version: bool,
+    strict_args: bool,
+    debug: bool,
+    quiet: bool,
+    format: bool,
+}
+
+impl Flags {
+    /// Parse the leading flags from `args`.
+    ///
+    /// Returns `None` when the flags were fully handled (e.g. `--help`).
+    #[expect(clippy::too_many_lines)]
+    fn parse<I: Iterator<Item = String>>(
+        args: &mut Peekable<I>,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let mut flags = Self::default();
+
+        'flags: while let Some(arg) =
+            args.next_if(|a| a.starts_with('-')).as_ref()
+        {
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    print_help();
+
+                    return Ok(None);
+                }
+                "-v" | "--validate" => {
+                    flags.validate = true;
+                }
+                "--validate-all" => {
+                    let dir = flag_value(args, arg, "a directory")?;
+                    flags.validate_all = Some(PathBuf::from(dir));
+                }
+                "-c" | "--check" => {
+                    flags.check = true;
+                }
+                "--count" => {
+                    flags.count = true;
+                }
+                "--stdin" => {
+                    flags.stdin = true;
+                }
+                "--print-sshd-config" => {
+                    flags.print_sshd_config = true;
+                }
+                "--lint" => {
+                    flags.lint = true;
+                }
+                "--lint-strict" => {
+                    flags.lint = true;
+                    flags.lint_strict = true;
+                }
+                "--dump-context" => {
+                    flags.dump_context = true;
+                }
+                "--format" => {
+                    flags.format = true;
+                }
+                "-q" | "--quiet" => {
+                    flags.quiet = true;
+                }
+                "--ignore-version" => {
+                    flags.ignore_version = true;
+                }
+                "--strict-args" => {
+                    flags.strict_args = true;
+                }
+                "-d" | "--debug" => {
+                    flags.debug = true;
+                }
+                "--timeout" => {
+                    let seconds =
+                        flag_value(args, arg, "a number of seconds")?;
+                    let timeout = seconds
+                        .parse()
+                        .ok()
+                        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                        .ok_or_else(|| {
+                            format!("invalid --timeout seconds: {seconds}")
+                        })?;
 
-                return Ok(ExitCode::SUCCESS);
+                    flags.timeout = Some(timeout);
+                }
+                "--command" => {
+                    let command = args
+                        .next()
+                        .as_deref()
+                        .and_then(parse_command)
+                        .ok_or("--command requires keys or principals")?;
+
+                    flags.command = Some(command);
+                }
+                "--error-format" => {
+                    flags.error_format = args
+                        .next()
+                        .as_deref()
+                        .and_then(ErrorFormat::parse)
+                        .ok_or("--error-format requires text or json")?;
+                }
+                "--color" => {
+                    flags.color = args
+                        .next()
+                        .as_deref()
+                        .and_then(Color::parse)
+                        .ok_or("--color requires auto, always or never")?;
+                }
+                "--no-color" => {
+                    flags.color = Color::Never;
+                }
+                "--output" => {
+                    let path = flag_value(args, arg, "a path")?;
+                    flags.output = Some(PathBuf::from(path));
+                }
+                "--name" => {
+                    flags.name = Some(flag_value(args, arg, "a name")?);
+                }
+                "--args-file" => {
+                    let path = flag_value(args, arg, "a path")?;
+                    flags.args_file = Some(PathBuf::from(path));
+                }
+                "-V" | "--version" => {
+                    println!("{} {}", env!("CARGO_PKG_NAME"), crate_version());
+
+                    return Ok(None);
+                }
+                "--" => break 'flags,
+                _ => {}
             }
-            "-v" | "--validate" => {
-                validate_arg = true;
+        }
+
+        // No need to validate separately since it done inside `render_to`.
+        flags.validate = flags.validate && !flags.check;
+
+        Ok(Some(flags))
+    }
+
+    /// The options of the render set by the flags.
+    const fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            ignore_version: self.ignore_version,
+            // `--check` appends placeholder arguments, which may be left
+            // over, a count mismatch is already warned about
+            strict_args: self.strict_args && !self.check,
+            debug: self.debug,
+        }
+    }
+}
+
+/// The value following `flag`, e.g. the path of `--output <path>`.
+fn flag_value<I: Iterator<Item = String>>(
+    args: &mut Peekable<I>,
+    flag: &str,
+    value: &str,
+) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{flag} requires {value}"))
+}
+
+fn parse_command(command: &str) -> Option<Command> {
+    [Command::Keys, Command::Principals, Command::Generic]
+        .into_iter()
+        .find(|c| c.as_str() == command)
+}
+
+/// The `list-tokens [keys|principals|generic]` subcommand.
+fn run_list_tokens<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let command = args
+        .next()
+        .map(|command| {
+            parse_command(&command).ok_or(
+                "list-tokens requires keys, principals, generic or nothing",
+            )
+        })
+        .transpose()?;
+
+    list_tokens(&mut io::stdout().lock(), command)?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The `init --command <command> [--tokens <tokens>]` subcommand.
+fn run_init<I: Iterator<Item = String>>(
+    mut args: Peekable<I>,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mut command = None;
+    let mut tokens = String::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--command" => {
+                let value = flag_value(&mut args, &arg, "a command")?;
+                command = Some(parse_command(&value).ok_or(
+                    "--command requires keys, principals or generic",
+                )?);
             }
-            "-c" | "--check" => {
-                check_arg = true;
+            "--tokens" => {
+                tokens = flag_value(&mut args, &arg, "tokens")?;
             }
-            "-V" | "--version" => {
-                println!("{} {}", env!("CARGO_PKG_NAME"), crate_version());
+            _ => return Err(format!("Unknown init argument: {arg}").into()),
+        }
+    }
+    let command = command.ok_or("init requires --command")?;
 
-                return Ok(ExitCode::SUCCESS);
-            }
-            "--" => break 'flags,
-            _ => {}
+    print!("{}", FrontMatter::scaffold(command, &tokens)?);
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_sshd_config<R: Read>(
+    reader: &mut BufReader<R>,
+    template_path: &str,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let front_matter = FrontMatter::parse(reader)?;
+    front_matter.validate()?;
+    if front_matter.command() == Command::Generic {
+        return Err("--print-sshd-config requires a keys or principals \
+                    template"
+            .into());
+    }
+
+    let program = env::current_exe()?;
+    let template_path = fs::canonicalize(template_path)?;
+    println!(
+        "{}",
+        front_matter.sshd_config(
+            &program.to_string_lossy(),
+            &template_path.to_string_lossy()
+        )
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Write `contents` to `path` through a temporary file in the same
+/// directory, which is renamed into place so `path` is never left truncated.
+/// An existing `path` keeps its permissions.
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fn write_sync(
+        file: &mut File,
+        contents: &[u8],
+        permissions: Option<fs::Permissions>,
+    ) -> io::Result<()> {
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()
+    }
+
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "path has no file name")
+    })?;
+    let permissions = match fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    // A random suffix, so the temporary file can't be guessed in advance
+    let (tmp_path, mut file) = loop {
+        let suffix = RandomState::new().build_hasher().finish();
+        let mut tmp_file_name = OsString::from(".");
+        tmp_file_name.push(file_name);
+        tmp_file_name.push(format!(".{suffix:016x}.tmp"));
+        let tmp_path = path.with_file_name(tmp_file_name);
+
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+        {
+            Ok(file) => break (tmp_path, file),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err),
+        }
+    };
+
+    let result = write_sync(&mut file, contents, permissions)
+        .and_then(|()| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+/// Split the positional arguments into template paths and token arguments.
+///
+/// Multiple templates are separated from the token arguments with `--`,
+/// otherwise only the first positional argument is a template. The `--` is
+/// only a separator after two or more template files, so a token argument,
+/// e.g. a key ID, can be `--`.
+fn split_templates(
+    stdin: bool,
+    mut positional: Vec<String>,
+) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
+    if stdin {
+        return Ok((Vec::new(), positional));
+    }
+
+    if positional.is_empty() {
+        return Err("No template path provided".into());
+    }
+
+    let templates_len = match positional.iter().position(|a| a == "--") {
+        Some(separator)
+            if separator > 1
+                && positional[..separator]
+                    .iter()
+                    .all(|path| Path::new(path).is_file()) =>
+        {
+            positional.remove(separator);
+            separator
+        }
+        _ => 1,
+    };
+    let args = positional.split_off(templates_len);
+
+    Ok((positional, args))
+}
+
+/// The `.tera` files in `dir` and its subdirectories, sorted.
+///
+/// Symlinked subdirectories aren't followed, they may loop.
+fn find_templates(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut templates = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            templates.extend(find_templates(&path)?);
+        } else if path.extension().is_some_and(|ext| ext == "tera") {
+            templates.push(path);
         }
     }
+    templates.sort();
+
+    Ok(templates)
+}
 
-    // No need to validate separately since it done inside `render_to`.
-    validate_arg = validate_arg && !check_arg;
+/// Validate the front matter of every template in `dir`, printing the result
+/// of each template and a summary. Fails when any template is invalid.
+fn validate_all(
+    dir: &Path,
+    flags: &Flags,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let templates = find_templates(dir)?;
 
-    let template_path = args.next().ok_or("No template path provided")?;
-    let template = File::open(&template_path)?;
-    let mut reader = BufReader::new(template);
+    let mut failed = 0;
+    for template in &templates {
+        let result = File::open(template)
+            .map_err(|err| FrontMatterError::ParseError(Box::new(err)))
+            .and_then(|file| {
+                parse_front_matter(&mut BufReader::new(file), flags)
+            })
+            .and_then(|front_matter| {
+                warn_unknown_tokens(&front_matter);
+                front_matter.validate_with(flags.render_options())
+            });
 
-    if validate_arg {
-        FrontMatter::parse(&mut reader)?.validate()?;
+        match result {
+            Ok(()) => println!("ok: {}", template.display()),
+            Err(err) => {
+                failed += 1;
+                println!("FAILED: {}", template.display());
+                print_error_chain(&err, flags);
+            }
+        }
+    }
+    println!("{} passed, {failed} failed", templates.len() - failed);
 
-        return Ok(ExitCode::SUCCESS);
+    if failed == 0 {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::from(EXIT_FRONT_MATTER))
     }
+}
+
+/// Open the templates with their paths, stdin first with `--stdin`.
+fn open_templates(
+    stdin: bool,
+    template_paths: Vec<String>,
+) -> io::Result<Vec<Template>> {
+    let mut templates: Vec<Template> =
+        Vec::with_capacity(template_paths.len().max(1));
+    if stdin {
+        // Buffer stdin so `--check` can rewind it
+        templates.push((
+            String::from("<stdin>"),
+            BufReader::new(buffer(io::stdin())?),
+        ));
+    }
+    for template_path in template_paths {
+        let mut template = File::open(&template_path)?;
+        // Pipes, e.g. `/dev/fd/63` from process substitution, can't be
+        // rewound for `--check`, buffer them like stdin
+        let reader: Box<dyn ReadSeek> = if template.stream_position().is_ok() {
+            Box::new(template)
+        } else {
+            buffer(template)?
+        };
+        templates.push((template_path, BufReader::new(reader)));
+    }
+
+    Ok(templates)
+}
+
+/// Read all of `reader` into memory, so it can be rewound.
+fn buffer<R: Read>(mut reader: R) -> io::Result<Box<dyn ReadSeek>> {
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+
+    Ok(Box::new(Cursor::new(buf)))
+}
 
-    #[expect(clippy::if_not_else)]
-    let (writer, args): (
-        &mut dyn Write,
-        &mut dyn Iterator<Item = String>,
-    ) = if !check_arg {
-        (&mut io::stdout(), &mut args)
+/// Read the newline separated arguments of `--args-file`, `-` is stdin.
+fn read_args_file(
+    path: &Path,
+    stdin: bool,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let contents = if path.as_os_str() == "-" {
+        if stdin {
+            return Err("--args-file - can't be used with --stdin".into());
+        }
+        io::read_to_string(io::stdin())?
     } else {
-        let front_matter = FrontMatter::parse(&mut reader)?;
-        front_matter.validate()?;
+        fs::read_to_string(path)?
+    };
+
+    Ok(contents.lines().map(String::from).collect())
+}
 
-        let placeholder_args = Token::get_template_args(front_matter.tokens());
+/// Parse the front matter, of the document for `--command` if given.
+fn parse_front_matter<R: Read>(
+    reader: &mut BufReader<R>,
+    flags: &Flags,
+) -> Result<FrontMatter, FrontMatterError> {
+    match flags.command {
+        Some(command) => FrontMatter::parse_for_command(reader, command)
+            .map(|(front_matter, _)| front_matter),
+        None => FrontMatter::parse(reader),
+    }
+}
+
+/// Parse the front matter and the body, of the document for `command` if
+/// given.
+fn parse_template<R: Read>(
+    reader: &mut BufReader<R>,
+    command: Option<Command>,
+) -> Result<(FrontMatter, String), SshdCommandError> {
+    if let Some(command) = command {
+        return Ok(FrontMatter::parse_for_command(reader, command)?);
+    }
+
+    let front_matter = FrontMatter::parse(reader)?;
+    let mut body = String::new();
+    reader
+        .read_to_string(&mut body)
+        .map_err(|e| SshdCommandError::Unknown(Box::new(e)))?;
+
+    Ok((front_matter, body))
+}
+
+fn render_template(
+    writer: &mut dyn Write,
+    mut reader: BufReader<Box<dyn ReadSeek>>,
+    template_path: &str,
+    args: &[String],
+    flags: &Flags,
+) -> Result<ExitCode, Box<dyn Error>> {
+    let mut args = args.to_vec();
+    let mut counter = LineCounter::default();
+    // Tera errors name the template, a full path would make them hard to read
+    let template_name = flags.name.as_deref().unwrap_or_else(|| {
+        Path::new(template_path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(template_path)
+    });
+
+    let writer: &mut dyn Write = if flags.check {
+        let front_matter = match parse_front_matter(&mut reader, flags)
+            .and_then(|front_matter| {
+                front_matter
+                    .validate_with(flags.render_options())
+                    .map(|()| front_matter)
+            }) {
+            Ok(front_matter) => front_matter,
+            Err(err) => {
+                print_error_chain(&err, flags);
+
+                return Ok(ExitCode::from(EXIT_FRONT_MATTER));
+            }
+        };
+
+        // Warn when the given arguments don't line up with the tokens
+        let expected_args = expected_arg_count(&front_matter, &args);
+        if !args.is_empty() && args.len() != expected_args {
+            eprintln!(
+                "Warning: tokens expect {expected_args} argument(s), but {} \
+                 were given",
+                args.len()
+            );
+        }
+
+        args.extend(Token::get_template_args(front_matter.tokens()));
 
         // Rewind reader
         _ = reader.seek(io::SeekFrom::Start(0))?;
 
-        (&mut io::empty(), &mut args.chain(placeholder_args))
+        &mut counter
+    } else {
+        writer
     };
 
-    if let Err(err) = render_to(writer, args, &template_path, reader) {
-        print_error_chain(&err);
+    let result = parse_template(&mut reader, flags.command).and_then(
+        |(front_matter, body)| {
+            warn_unknown_tokens(&front_matter);
+            if flags.ignore_version {
+                if let Err(err) = front_matter.check_version() {
+                    eprintln!("Warning: {err}");
+                }
+            }
 
-        return Ok(ExitCode::FAILURE);
+            if flags.check {
+                warn_undefined_variables(&front_matter, &args, &body);
+            }
+            if flags.debug {
+                debug_front_matter(&front_matter);
+            }
+            render_parsed(
+                writer,
+                args.into_iter(),
+                template_name,
+                front_matter,
+                flags.render_options(),
+                &body,
+            )
+        },
+    );
+    if let Err(err) = result {
+        print_error_chain(&err, flags);
+
+        return Ok(error_exit_code(&err));
+    }
+
+    if flags.check && flags.count {
+        eprintln!("{template_path}: {} non-empty line(s)", counter.lines());
     }
 
     Ok(ExitCode::SUCCESS)
 }
+
+/// The number of arguments the tokens of `front_matter` expect from
+/// `args`, `%C` is a single argument when it's quoted in sshd_config.
+fn expected_arg_count(front_matter: &FrontMatter, args: &[String]) -> usize {
+    let mut index = 0;
+    for token in front_matter.tokens() {
+        if matches!(token, Token::ConnectionEndpoints)
+            && args
+                .get(index)
+                .is_some_and(|arg| arg.split_whitespace().count() == 4)
+        {
+            return front_matter.arg_count() - 3;
+        }
+        index += token.arg_count();
+    }
+
+    front_matter.arg_count()
+}
+
+/// Warn about ignored unknown tokens that are most likely a typo.
+fn warn_unknown_tokens(front_matter: &FrontMatter) {
+    for warning in front_matter.unknown_token_warnings() {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+/// Trace the parsed front matter and the version check for `--debug`.
+fn debug_front_matter(front_matter: &FrontMatter) {
+    let tokens: Vec<String> = front_matter
+        .tokens()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    eprintln!(
+        "debug: front matter parsed: {} with tokens `{}`",
+        front_matter.command(),
+        tokens.join(" ")
+    );
+    match front_matter.check_version() {
+        Ok(()) => {
+            eprintln!("debug: version check passed for {}", crate_version());
+        }
+        Err(err) => eprintln!("debug: version check failed: {err}"),
+    }
+}
+
+/// Warn about variables the template body references that aren't defined,
+/// even behind conditionals the placeholder render doesn't take.
+fn warn_undefined_variables(
+    front_matter: &FrontMatter,
+    args: &[String],
+    body: &str,
+) {
+    // Errors are left to the render that follows
+    if let Ok(undefined) =
+        undefined_variables(front_matter.clone(), args.iter().cloned(), body)
+    {
+        for variable in undefined {
+            eprintln!("Warning: variable `{variable}` is not defined");
+        }
+    }
+}
+
+/// Print `err` with its sources and return the exit code for other errors.
+fn report_error(err: &dyn Error, flags: &Flags) -> ExitCode {
+    print_error_chain(err, flags);
+
+    ExitCode::FAILURE
+}
+
+/// Run `run` on a worker thread, failing when it doesn't finish within
+/// `timeout`.
+///
+/// The worker can't be cancelled, it's abandoned when the process exits.
+fn run_with_timeout(
+    flags: Flags,
+    args: Vec<String>,
+    timeout: Duration,
+) -> ExitCode {
+    let (sender, receiver) = mpsc::channel();
+    let worker_flags = flags.clone();
+    thread::spawn(move || {
+        // Errors aren't `Send`, so they're printed by the worker
+        let exit_code = run(&worker_flags, args)
+            .unwrap_or_else(|err| report_error(err.as_ref(), &worker_flags));
+        _ = sender.send(exit_code);
+    });
+
+    let err: Box<dyn Error> = match receiver.recv_timeout(timeout) {
+        Ok(exit_code) => return exit_code,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            format!("timed out after {}s", timeout.as_secs_f64()).into()
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => {
+            "worker thread panicked".into()
+        }
+    };
+
+    report_error(err.as_ref(), &flags)
+}
+
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1).peekable();
+
+    // Subcommands only come first, so `--check init` renders a template
+    // named `init`
+    let subcommand = if args.next_if(|arg| arg == "list-tokens").is_some() {
+        run_list_tokens(args)
+    } else if args.next_if(|arg| arg == "init").is_some() {
+        run_init(args)
+    } else {
+        return run_main(args);
+    };
+
+    subcommand
+        .unwrap_or_else(|err| report_error(err.as_ref(), &Flags::default()))
+}
+
+/// Parse the flags and run without a subcommand.
+fn run_main<I: Iterator<Item = String>>(mut args: Peekable<I>) -> ExitCode {
+    let flags = match Flags::parse(&mut args) {
+        Ok(Some(flags)) => flags,
+        Ok(None) => return ExitCode::SUCCESS,
+        Err(err) => return report_error(err.as_ref(), &Flags::default()),
+    };
+    let args = args.collect();
+
+    match flags.timeout {
+        Some(timeout) => run_with_timeout(flags, args, timeout),
+        None => run(&flags, args)
+            .unwrap_or_else(|err| report_error(err.as_ref(), &flags)),
+    }
+}
+
+fn run(flags: &Flags, args: Vec<String>) -> Result<ExitCode, Box<dyn Error>> {
+    if let Some(dir) = &flags.validate_all {
+        return validate_all(dir, flags);
+    }
+
+    let (template_paths, mut args) = split_templates(flags.stdin, args)?;
+
+    if let Some(path) = &flags.args_file {
+        args.extend(read_args_file(path, flags.stdin)?);
+    }
+
+    let mut templates = open_templates(flags.stdin, template_paths)?;
+
+    if flags.print_sshd_config {
+        return match templates.as_mut_slice() {
+            [(template_path, reader)] if !flags.stdin => {
+                print_sshd_config(reader, template_path)
+            }
+            _ => {
+                Err("--print-sshd-config requires a single template path"
+                    .into())
+            }
+        };
+    }
+
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut stdout = io::stdout();
+    // With `--output` everything is rendered before anything is written
+    let mut buffer = Vec::new();
+    let writer: &mut dyn Write = if flags.output.is_some() {
+        &mut buffer
+    } else {
+        &mut stdout
+    };
+    let mut needs_newline = false;
+    for (template_path, mut reader) in templates {
+        if flags.validate {
+            if let Err(err) = parse_front_matter(&mut reader, flags).and_then(
+                |front_matter| {
+                    warn_unknown_tokens(&front_matter);
+                    front_matter.validate()
+                },
+            ) {
+                print_error_chain(&err, flags);
+
+                return Ok(ExitCode::from(EXIT_FRONT_MATTER));
+            }
+            continue;
+        }
+
+        if flags.lint {
+            if lint(&mut reader, flags.lint_strict)? != ExitCode::SUCCESS {
+                exit_code = ExitCode::FAILURE;
+            }
+            continue;
+        }
+
+        if flags.format {
+            let mut template = String::new();
+            reader.read_to_string(&mut template)?;
+            match FrontMatter::format(&template) {
+                Ok(formatted) => writer.write_all(formatted.as_bytes())?,
+                Err(err) => {
+                    print_error_chain(&err, flags);
+
+                    return Ok(ExitCode::from(EXIT_FRONT_MATTER));
+                }
+            }
+            continue;
+        }
+
+        if flags.dump_context {
+            match context_to_json(args.iter().cloned(), reader) {
+                Ok(context) => {
+                    println!("{}", serde_json::to_string_pretty(&context)?);
+                }
+                Err(err) => {
+                    print_error_chain(&err, flags);
+
+                    return Ok(error_exit_code(&err));
+                }
+            }
+            continue;
+        }
+
+        // Outputs of multiple templates are newline joined
+        let mut output = Vec::new();
+        let render_exit_code = render_template(
+            &mut output,
+            reader,
+            &template_path,
+            &args,
+            flags,
+        )?;
+        if render_exit_code != ExitCode::SUCCESS {
+            return Ok(render_exit_code);
+        }
+
+        if needs_newline && !output.is_empty() {
+            writer.write_all(b"\n")?;
+        }
+        writer.write_all(&output)?;
+        if !output.is_empty() {
+            needs_newline = !output.ends_with(b"\n");
+        }
+    }
+
+    // Only replace the output file with rendered templates
+    let rendered =
+        !(flags.check || flags.validate || flags.lint || flags.dump_context);
+    if let Some(path) = flags.output.as_deref().filter(|_| rendered) {
+        write_atomic(path, &buffer)?;
+    }
+
+    Ok(exit_code)
+}
@@ -1,5 +1,5 @@
 use assert_cmd::Command;
-use predicates::prelude::predicate;
+use predicates::prelude::{predicate, PredicateBooleanExt};
 
 fn cmd() -> Command {
     Command::cargo_bin("sshd-command").expect("binary exists")
@@ -7,59 +7,768 @@ fn cmd() -> Command {
 
 #[cfg(test)]
 mod happy_path {
+    use std::{env, fs};
+
     use super::*;
 
     #[test]
     fn argument_help() {
         let mut cmd = cmd();
-        cmd.arg("--help");
+        cmd.arg("--help");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::contains("USAGE"));
+    }
+
+    #[test]
+    fn argument_version() {
+        let mut cmd = cmd();
+        cmd.arg("-V");
+        cmd.assert().success();
+    }
+
+    #[test]
+    fn list_tokens() {
+        let mut cmd = cmd();
+        cmd.arg("list-tokens");
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with(
+                "TOKEN  KEYS  PRINCIPALS  DESCRIPTION ",
+            ))
+            .stdout(predicate::str::is_match(
+                "\n%T     no    yes         The type of the CA key. +ssh-ed25519\n",
+            ).unwrap());
+    }
+
+    #[test]
+    fn init() {
+        let mut cmd1 = cmd();
+        cmd1.args(["init", "--command", "principals", "--tokens", "%U %u"]);
+        let output = cmd1.assert().success().get_output().stdout.clone();
+        let template = String::from_utf8(output).unwrap();
+
+        assert!(template.contains("command: principals"), "{template}");
+        assert!(template.contains("tokens: '%U %u'"), "{template}");
+
+        let mut cmd2 = cmd();
+        cmd2.args(["--check", "--stdin"]).write_stdin(template);
+        cmd2.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn list_tokens_keys() {
+        let mut cmd = cmd();
+        cmd.args(["list-tokens", "keys"]);
+        cmd.assert().success().stdout(
+            predicate::str::is_match(
+                "\n%T     no         The type of the CA key. +ssh-ed25519\n",
+            )
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn validate_principals() {
+        let mut cmd = cmd();
+        cmd.args(["--validate", "tests/fixtures/happy/principals.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_principals() {
+        let mut cmd = cmd();
+        cmd.args(["--check", "tests/fixtures/happy/principals.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_principals() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/principals.tera", "1000", "user"]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/principals.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_json_principals() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/json-principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/principals.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_readme_principals() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/readme-principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/readme-principals.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_routing_domain() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/routing-domain.tera",
+            "rdomain0",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/routing-domain.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_cert_key_type() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/cert-key-type.tera",
+            "ssh-ed25519-cert-v01@openssh.com",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/cert-key-type.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_ca_key_type() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/ca-key-type.tera",
+            "ssh-ed25519",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/ca-key-type.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_key_fingerprint() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/key-fingerprint.tera",
+            "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+            "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("user\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_auth_key() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/auth-key.tera",
+            "X0Jhc2U2NEVuY29kZWRBdXRoS2V5T3JDZXJ0Xw==",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/auth-key.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_auth_key() {
+        let mut cmd = cmd();
+        cmd.args(["--check", "tests/fixtures/happy/auth-key.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_key_id() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/key-id.tera", "user@example.com"]);
+        cmd.assert()
+            .success()
+            .stdout("key id: user@example.com\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_key_id_separator() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/key-id.tera", "--"]);
+        cmd.assert()
+            .success()
+            .stdout("key id: --\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_key_id_numeric() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/key-id.tera", "42"]);
+        cmd.assert()
+            .success()
+            .stdout("key id: 42\nkey id (numeric): 42\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_connection_endpoints() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/connection-endpoints.tera",
+            "192.0.2.1",
+            "41644",
+            "::1",
+            "22",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("192.0.2.1 41644 ::1 22\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_connection_endpoints_scoped() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/connection-endpoints.tera",
+            "fe80::1%eth0",
+            "41644",
+            "fe80::2%eth0",
+            "22",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("fe80::1 41644 fe80::2 22\neth0 eth0\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_groups_filter() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/groups-filter.tera", "0"]);
+        cmd.assert()
+            .success()
+            .stdout("0\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_has_group() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/has-group.tera", "0"]);
+        cmd.assert()
+            .success()
+            .stdout("admin\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_unknown_tokens() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/unknown-tokens.tera", "user", "z"]);
+        cmd.assert()
+            .success()
+            .stdout("user@z\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_unknown_token_case_warning() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/unknown-token-case.tera",
+            "user",
+            "h",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("user\n")
+            .stderr("Warning: unknown token %H; did you mean %h?\n");
+    }
+
+    #[test]
+    fn output_static_principals() {
+        let mut cmd = cmd();
+        cmd.arg("tests/fixtures/happy/static-principals.tera");
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/static-principals.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_custom_delimiters() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/custom-delimiters.tera", "user"]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/custom-delimiters.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_ignore_version() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--ignore-version",
+            "tests/fixtures/happy/newer-version.tera",
+            "user",
+        ]);
+        cmd.assert().success().stdout("user\n").stderr(
+            predicate::str::starts_with(
+                "Warning: template requires sshd-command version >=999.0.0",
+            ),
+        );
+    }
+
+    #[test]
+    fn format_template() {
+        let mut cmd = cmd();
+        cmd.args(["--format", "tests/fixtures/happy/unformatted.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/unformatted.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_static_principals() {
+        let mut cmd = cmd();
+        cmd.args(["--check", "tests/fixtures/happy/static-principals.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_count() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--check",
+            "--count",
+            "tests/fixtures/happy/static-principals.tera",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(
+                "tests/fixtures/happy/static-principals.tera: 2 non-empty \
+                 line(s)\n",
+            );
+    }
+
+    #[test]
+    fn output_principals_to_file() {
+        let path = env::temp_dir().join("sshd-command-output-principals");
+
+        let mut cmd = cmd();
+        cmd.args(["--output"]).arg(&path).args([
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            include_str!("fixtures/happy/principals.out")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn output_principals_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join("sshd-command-output-permissions");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut cmd = cmd();
+        cmd.args(["--output"]).arg(&path).args([
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert().success();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn validate_all_symlink_loop() {
+        let dir = env::temp_dir().join("sshd-command-validate-all-loop");
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::copy(
+            "tests/fixtures/validate-all/good.tera",
+            dir.join("good.tera"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(".", dir.join("loop")).unwrap();
+
+        let mut cmd = cmd();
+        cmd.arg("--validate-all").arg(&dir);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::ends_with("1 passed, 0 failed\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_multi_command() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--command",
+            "principals",
+            "tests/fixtures/happy/multi-command.tera",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout("user@home.arpa\n")
+            .stderr(predicate::str::is_empty());
+
+        let mut cmd = super::cmd();
+        cmd.args([
+            "--command",
+            "keys",
+            "tests/fixtures/happy/multi-command.tera",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::ends_with(" user\n"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_multi_command() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--check",
+            "--command",
+            "keys",
+            "tests/fixtures/happy/multi-command.tera",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_env_fallback() {
+        let mut cmd = cmd();
+        cmd.arg("tests/fixtures/happy/env-fallback.tera")
+            .env("SSHD_CMD_USER", "user")
+            .env("SSHD_CMD_UID", "1000");
+        cmd.assert()
+            .success()
+            .stdout("user 1000\n")
+            .stderr(predicate::str::is_empty());
+
+        // Positional arguments take precedence
+        let mut cmd = super::cmd();
+        cmd.args(["tests/fixtures/happy/env-fallback.tera", "admin"])
+            .env("SSHD_CMD_USER", "user")
+            .env("SSHD_CMD_UID", "1000");
+        cmd.assert()
+            .success()
+            .stdout("admin 1000\n")
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_principals_debug() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--debug",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/principals.out"))
+            .stderr(
+                predicate::str::contains(
+                    "debug: front matter parsed: AuthorizedPrincipalsCommand \
+                     with tokens `%U %u`",
+                )
+                .and(predicate::str::contains(
+                    "debug: token %U\ndebug:   argument `1000`\n\
+                     debug: token %u\ndebug:   argument `user`\n",
+                ))
+                .and(predicate::str::contains("debug: context keys: "))
+                .and(predicate::str::contains(
+                    "debug: rendered principals.tera",
+                )),
+            );
+    }
+
+    #[test]
+    fn output_principals_stdin() {
+        let mut cmd = cmd();
+        cmd.args(["--stdin", "1000", "user"]);
+        cmd.write_stdin(include_str!("fixtures/happy/principals.tera"));
+        cmd.assert()
+            .success()
+            .stdout(include_str!("fixtures/happy/principals.out"))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_principals_stdin() {
+        let mut cmd = cmd();
+        cmd.args(["--stdin", "--check"]);
+        cmd.write_stdin(include_str!("fixtures/happy/principals.tera"));
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn check_principals_pipe() {
+        // A pipe can't be rewound, like process substitution `<(...)`
+        let mut cmd = cmd();
+        cmd.args(["--check", "/dev/stdin"]);
+        cmd.write_stdin(include_str!("fixtures/happy/principals.tera"));
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn print_sshd_config_principals() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--print-sshd-config",
+            "tests/fixtures/happy/principals.tera",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::starts_with(
+                "AuthorizedPrincipalsCommand /",
+            ))
+            .stdout(predicate::str::ends_with(
+                "tests/fixtures/happy/principals.tera %U %u\n",
+            ))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn lint_principals() {
+        let mut cmd = cmd();
+        cmd.args(["--lint-strict", "tests/fixtures/happy/principals.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn lint_custom_delimiters() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--lint-strict",
+            "tests/fixtures/happy/custom-delimiters.tera",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn lint_unused_token() {
+        let mut cmd = cmd();
+        cmd.args(["--lint", "tests/fixtures/sad/unused-token.tera"]);
+        cmd.assert().success().stderr(predicate::str::contains(
+            "token %C is never used in the template",
+        ));
+    }
+
+    #[test]
+    fn output_multiple_templates() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/principals.tera",
+            "tests/fixtures/happy/json-principals.tera",
+            "--",
+            "1000",
+            "user",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(format!(
+                "{}{}",
+                include_str!("fixtures/happy/principals.out"),
+                include_str!("fixtures/happy/principals.out"),
+            ))
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_verify_key_type() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/verify-key-type.tera",
+            "ssh-ed25519",
+            "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(
+                "ssh-ed25519 \
+                 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n",
+            )
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_args_file() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--args-file",
+            "tests/fixtures/happy/verify-key-type.args",
+            "tests/fixtures/happy/verify-key-type.tera",
+        ]);
+        cmd.assert()
+            .success()
+            .stdout(
+                "ssh-ed25519 \
+                 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n",
+            )
+            .stderr(predicate::str::is_empty());
+    }
+
+    #[test]
+    fn output_args_file_stdin() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--args-file",
+            "-",
+            "tests/fixtures/happy/verify-key-type.tera",
+            "ssh-ed25519",
+        ]);
+        cmd.write_stdin(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n",
+        );
         cmd.assert()
             .success()
-            .stdout(predicate::str::contains("USAGE"));
+            .stdout(
+                "ssh-ed25519 \
+                 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n",
+            )
+            .stderr(predicate::str::is_empty());
     }
 
     #[test]
-    fn argument_version() {
-        let mut cmd = cmd();
-        cmd.arg("-V");
-        cmd.assert().success();
+    fn output_deny() {
+        let mut cmd1 = cmd();
+        cmd1.args(["tests/fixtures/happy/deny.tera", "admin"]);
+        cmd1.assert()
+            .success()
+            .stdout("admin\n")
+            .stderr(predicate::str::is_empty());
+
+        // Denied
+        let mut cmd2 = cmd();
+        cmd2.args(["tests/fixtures/happy/deny.tera", "user"]);
+        cmd2.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::is_empty());
     }
 
     #[test]
-    fn validate_principals() {
+    fn dump_context_principals() {
         let mut cmd = cmd();
-        cmd.args(["--validate", "tests/fixtures/happy/principals.tera"]);
+        cmd.args([
+            "--dump-context",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+        ]);
         cmd.assert()
             .success()
-            .stdout(predicate::str::is_empty())
+            .stdout(predicate::str::contains(r#""hostname": "hostname""#))
+            .stdout(predicate::str::contains(r#""name": "user""#))
+            .stdout(predicate::str::contains(r#""uid": 1000"#))
             .stderr(predicate::str::is_empty());
     }
 
     #[test]
-    fn check_principals() {
+    fn output_jsonl_principals() {
         let mut cmd = cmd();
-        cmd.args(["--check", "tests/fixtures/happy/principals.tera"]);
+        cmd.args(["tests/fixtures/happy/jsonl-principals.tera", "user"]);
         cmd.assert()
             .success()
-            .stdout(predicate::str::is_empty())
+            .stdout(include_str!("fixtures/happy/jsonl-principals.out"))
             .stderr(predicate::str::is_empty());
     }
 
     #[test]
-    fn output_principals() {
+    fn output_defaults() {
         let mut cmd = cmd();
-        cmd.args(["tests/fixtures/happy/principals.tera", "1000", "user"]);
+        cmd.args(["tests/fixtures/happy/defaults.tera", "user"]);
         cmd.assert()
             .success()
-            .stdout(include_str!("fixtures/happy/principals.out"))
+            .stdout("user@rdomain0\n")
+            .stderr(predicate::str::is_empty());
+
+        let mut cmd = super::cmd();
+        cmd.args(["tests/fixtures/happy/defaults.tera", "user", "rdomain1"]);
+        cmd.assert()
+            .success()
+            .stdout("user@rdomain1\n")
             .stderr(predicate::str::is_empty());
     }
 
     #[test]
-    fn output_json_principals() {
+    fn output_principals_timeout() {
         let mut cmd = cmd();
         cmd.args([
-            "tests/fixtures/happy/json-principals.tera",
+            "--timeout",
+            "10",
+            "tests/fixtures/happy/principals.tera",
             "1000",
             "user",
         ]);
@@ -70,22 +779,20 @@ mod happy_path {
     }
 
     #[test]
-    fn output_readme_principals() {
+    fn check_ca_key_type() {
         let mut cmd = cmd();
-        cmd.args([
-            "tests/fixtures/happy/readme-principals.tera",
-            "1000",
-            "user",
-        ]);
+        cmd.args(["--check", "tests/fixtures/happy/ca-key-type.tera"]);
         cmd.assert()
             .success()
-            .stdout(include_str!("fixtures/happy/readme-principals.out"))
+            .stdout(predicate::str::is_empty())
             .stderr(predicate::str::is_empty());
     }
 }
 
 #[cfg(test)]
 mod sad_path {
+    use std::{env, fs};
+
     use super::*;
 
     #[test]
@@ -109,7 +816,73 @@ mod sad_path {
     fn unsupported_token() {
         let mut cmd = cmd();
         cmd.args(["--validate", "tests/fixtures/sad/unsupported-token.tera"]);
-        cmd.assert().failure();
+        cmd.assert().failure().code(2);
+    }
+
+    #[test]
+    fn validate_all() {
+        let mut cmd = cmd();
+        cmd.args(["--validate-all", "tests/fixtures/validate-all"]);
+        cmd.assert()
+            .failure()
+            .code(2)
+            .stdout(
+                "ok: tests/fixtures/validate-all/good.tera\n\
+                 FAILED: tests/fixtures/validate-all/nested/bad.tera\n\
+                 1 passed, 1 failed\n",
+            )
+            .stderr(
+                "Error: %T is not a valid token for AuthorizedKeysCommand\n",
+            );
+    }
+
+    #[test]
+    fn check_unsupported_token() {
+        let mut cmd = cmd();
+        cmd.args(["--check", "tests/fixtures/sad/unsupported-token.tera"]);
+        cmd.assert().failure().code(2);
+    }
+
+    #[test]
+    fn strict_args() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--strict-args",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+            "extra",
+        ]);
+        cmd.assert()
+            .code(1)
+            .stdout(predicate::str::is_empty())
+            .stderr("Error: 1 argument(s) left over after the last token\n");
+    }
+
+    #[test]
+    fn strict_args_debug() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--strict-args",
+            "--debug",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+            "extra",
+        ]);
+        cmd.assert().code(1).stderr(
+            predicate::str::contains("debug:   argument `user`")
+                .and(predicate::str::contains("argument `extra`").not()),
+        );
+    }
+
+    #[test]
+    fn newer_version() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/newer-version.tera", "user"]);
+        cmd.assert().code(2).stderr(predicate::str::contains(
+            "template requires sshd-command version >=999.0.0",
+        ));
     }
 
     #[test]
@@ -120,7 +893,7 @@ mod sad_path {
             "tests/fixtures/sad/missing-token-complete-user.tera",
         ]);
         cmd.assert().failure().stderr(predicate::str::contains(
-            "token required for `complete_user",
+            "`%U` or `%u` token required for `complete_user = true`",
         ));
     }
 
@@ -133,6 +906,76 @@ mod sad_path {
         ));
     }
 
+    #[test]
+    fn missing_tera_context_quiet() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--quiet",
+            "--check",
+            "tests/fixtures/sad/missing-context.tera",
+        ]);
+        cmd.assert().failure().stderr(
+            "Warning: variable `does_not_exist` is not defined\nError: tera\n",
+        );
+    }
+
+    #[test]
+    fn check_guarded_undefined_variable() {
+        let mut cmd = cmd();
+        cmd.args(["--check", "tests/fixtures/sad/guarded-undefined.tera"]);
+        cmd.assert()
+            .success()
+            .stdout(predicate::str::is_empty())
+            .stderr("Warning: variable `admin_principal` is not defined\n");
+    }
+
+    #[test]
+    fn missing_tera_context_name() {
+        let path = env::current_dir()
+            .unwrap()
+            .join("tests/fixtures/sad/missing-context.tera");
+
+        let mut cmd1 = cmd();
+        cmd1.arg(&path);
+        cmd1.assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "Caused by: Failed to render 'missing-context.tera'\n",
+            ))
+            .stderr(predicate::str::contains("tests/fixtures").not());
+
+        let mut cmd2 = cmd();
+        cmd2.args(["--name", "principals"]).arg(&path);
+        cmd2.assert().failure().stderr(predicate::str::contains(
+            "Caused by: Failed to render 'principals'\n",
+        ));
+    }
+
+    #[test]
+    fn missing_tera_context_color() {
+        let mut cmd1 = cmd();
+        cmd1.args([
+            "--color",
+            "never",
+            "tests/fixtures/sad/missing-context.tera",
+        ]);
+        cmd1.assert()
+            .failure()
+            .stderr(predicate::str::starts_with("Error: tera\n"))
+            .stderr(predicate::str::contains("\x1b").not());
+
+        let mut cmd2 = cmd();
+        cmd2.args([
+            "--color",
+            "always",
+            "tests/fixtures/sad/missing-context.tera",
+        ]);
+        cmd2.env("NO_COLOR", "1");
+        cmd2.assert().failure().stderr(predicate::str::starts_with(
+            "\x1b[1;31mError:\x1b[0m tera\n\x1b[2mCaused by:\x1b[0m ",
+        ));
+    }
+
     #[test]
     fn validate_and_check() {
         // Front matter is valid
@@ -147,6 +990,226 @@ mod sad_path {
             "--check",
             "tests/fixtures/sad/missing-context.tera",
         ]);
-        cmd2.assert().failure();
+        cmd2.assert().failure().code(3);
+    }
+
+    #[test]
+    fn empty_routing_domain() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/routing-domain.tera", "", "user"]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("token %D has invalid argument"));
+    }
+
+    #[test]
+    fn unknown_cert_key_type_strict() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/cert-key-type.tera", "ssh-foo"]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "token %t has invalid argument: ssh-foo",
+        ));
+    }
+
+    #[test]
+    fn invalid_key_fingerprint() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/key-fingerprint.tera",
+            "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+            "uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s",
+            "user",
+        ]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("token %f has invalid argument"));
+    }
+
+    #[test]
+    fn invalid_auth_key() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/happy/auth-key.tera", "not base64!"]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("token %k has invalid argument"));
+    }
+
+    #[test]
+    fn check_argument_count_mismatch() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--check",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+            "extra",
+        ]);
+        cmd.assert().success().stderr(predicate::str::contains(
+            "tokens expect 2 argument(s), but 3 were given",
+        ));
+    }
+
+    #[test]
+    fn check_joined_connection_endpoints() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--check",
+            "tests/fixtures/happy/connection-endpoints.tera",
+            "::1 41644 ::1 22",
+        ]);
+        cmd.assert()
+            .success()
+            .stderr(predicate::str::contains("tokens expect").not());
+    }
+
+    #[test]
+    fn lint_strict_unused_token() {
+        let mut cmd = cmd();
+        cmd.args(["--lint-strict", "tests/fixtures/sad/unused-token.tera"]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "token %C is never used in the template",
+        ));
+    }
+
+    #[test]
+    fn verify_key_type_mismatch() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/verify-key-type.tera",
+            "ssh-rsa",
+            "AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f",
+        ]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("token %t has invalid argument"));
+    }
+
+    #[test]
+    fn has_group_without_complete_user() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/sad/has-group.tera", "0"]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "has_group: requires `complete_user: true`",
+        ));
+    }
+
+    #[test]
+    fn unknown_token() {
+        let mut cmd = cmd();
+        cmd.args(["tests/fixtures/sad/unknown-token.tera", "user", "z"]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "unknown token %Z, accepted with `ignore_unknown_tokens`",
+        ));
+    }
+
+    #[test]
+    fn missing_connection_endpoints_argument() {
+        let mut cmd = cmd();
+        cmd.args([
+            "tests/fixtures/happy/connection-endpoints.tera",
+            "192.0.2.1",
+            "41644",
+        ]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "token %C missing argument 3 (server address)",
+        ));
+    }
+
+    #[test]
+    fn output_to_file_unchanged_on_error() {
+        let path = env::temp_dir().join("sshd-command-output-unchanged");
+        fs::write(&path, "previous\n").unwrap();
+
+        let mut cmd = cmd();
+        cmd.args(["--output"])
+            .arg(&path)
+            .args(["tests/fixtures/happy/principals.tera", "1000"]);
+        cmd.assert().failure();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "previous\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_command_document() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--command",
+            "keys",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "no front matter document for AuthorizedKeysCommand",
+        ));
+    }
+
+    #[test]
+    fn missing_token_error_format_json() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--error-format",
+            "json",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+        ]);
+        cmd.assert()
+            .failure()
+            .stderr("[{\"message\":\"token %u has missing argument(s)\"}]\n");
+    }
+
+    #[test]
+    fn non_existent_template_path_error_format_json() {
+        let mut cmd = cmd();
+        cmd.args(["--error-format", "json", "test/file/doesnt/exist"]);
+        cmd.assert().failure().stderr(
+            "[{\"message\":\"No such file or directory (os error 2)\"}]\n",
+        );
+    }
+
+    #[test]
+    fn env_fallback_missing() {
+        let mut cmd = cmd();
+        cmd.arg("tests/fixtures/happy/env-fallback.tera")
+            .env("SSHD_CMD_USER", "user")
+            .env_remove("SSHD_CMD_UID");
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("token %U has missing argument"));
+    }
+
+    #[test]
+    fn invalid_timeout() {
+        let mut cmd = cmd();
+        cmd.args([
+            "--timeout",
+            "-1",
+            "tests/fixtures/happy/principals.tera",
+            "1000",
+            "user",
+        ]);
+        cmd.assert()
+            .failure()
+            .stderr(predicate::str::contains("invalid --timeout seconds"));
+    }
+
+    #[test]
+    fn list_tokens_after_flags() {
+        let mut cmd = cmd();
+        cmd.args(["--quiet", "list-tokens"]);
+        cmd.assert()
+            .failure()
+            .stdout(predicate::str::is_empty())
+            .stderr(predicate::str::contains("No such file or directory"));
+    }
+
+    #[test]
+    fn list_tokens_unknown_command() {
+        let mut cmd = cmd();
+        cmd.args(["list-tokens", "certificates"]);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "list-tokens requires keys, principals, generic or nothing",
+        ));
     }
 }